@@ -0,0 +1,54 @@
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+#include "../runtime.h"
+
+// The runtime side of `silverfish --abi-profile`, for the toolchain imports that need more than a generated shim
+// Like the WASI calls, these get the linear memory base and size ahead of their wasm arguments
+
+// Emscripten
+
+i32 emscripten_memcpy_big_lowered(u8* memory, u32 memory_size, u32 dest, u32 src, u32 num) {
+    silverfish_assert((u64) dest + num <= memory_size);
+    silverfish_assert((u64) src + num <= memory_size);
+    memmove(memory + dest, memory + src, num);
+    return dest;
+}
+
+// AssemblyScript
+
+// Strings are UTF-16, with their length in bytes stored just before them, we only print the ASCII part
+static void print_assemblyscript_string(u8* memory, u32 memory_size, u32 offset) {
+    if (offset < 4 || offset > memory_size) {
+        printf("<invalid string>");
+        return;
+    }
+    u32 byte_length;
+    memcpy(&byte_length, memory + offset - 4, sizeof(byte_length));
+    if ((u64) offset + byte_length > memory_size) {
+        printf("<invalid string>");
+        return;
+    }
+    for (u32 i = 0; i + 1 < byte_length; i += 2) {
+        u16 c = memory[offset + i] | (memory[offset + i + 1] << 8);
+        putchar(c < 128 ? c : '?');
+    }
+}
+
+void assemblyscript_abort(u8* memory, u32 memory_size, u32 message, u32 file_name, i32 line, i32 column) {
+    printf("abort: ");
+    if (message != 0) {
+        print_assemblyscript_string(memory, memory_size, message);
+    }
+    printf(" at ");
+    if (file_name != 0) {
+        print_assemblyscript_string(memory, memory_size, file_name);
+    }
+    printf(":%d:%d\n", line, column);
+    abort();
+}
+
+double assemblyscript_seed(u8* memory, u32 memory_size) {
+    return (double) time(NULL);
+}
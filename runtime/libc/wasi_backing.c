@@ -0,0 +1,266 @@
+#include <errno.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sched.h>
+#include <time.h>
+#include <unistd.h>
+
+#include <sys/stat.h>
+#ifdef __APPLE__
+#include <sys/random.h>
+#endif
+
+#include "../runtime.h"
+
+// The runtime side of `silverfish --wasi`
+// The compiler has already bounds checked every pointer argument, and converted it to a host pointer
+// Offsets nested inside what we are passed (iovecs, argv arrays) still have to be checked here, against memory_size
+
+#define WASI_ESUCCESS 0
+#define WASI_EACCES 2
+#define WASI_EAGAIN 6
+#define WASI_EBADF 8
+#define WASI_EFAULT 21
+#define WASI_EINTR 27
+#define WASI_EINVAL 28
+#define WASI_EIO 29
+#define WASI_EISDIR 31
+#define WASI_ENOENT 44
+#define WASI_ENOSPC 51
+#define WASI_ENOSYS 52
+#define WASI_EPIPE 64
+#define WASI_ESPIPE 70
+
+#define WASI_FILETYPE_UNKNOWN 0
+#define WASI_FILETYPE_BLOCK_DEVICE 1
+#define WASI_FILETYPE_CHARACTER_DEVICE 2
+#define WASI_FILETYPE_DIRECTORY 3
+#define WASI_FILETYPE_REGULAR_FILE 4
+
+static u32 wasi_errno(int e) {
+    switch (e) {
+        case EACCES: return WASI_EACCES;
+        case EAGAIN: return WASI_EAGAIN;
+        case EBADF: return WASI_EBADF;
+        case EFAULT: return WASI_EFAULT;
+        case EINTR: return WASI_EINTR;
+        case EINVAL: return WASI_EINVAL;
+        case EISDIR: return WASI_EISDIR;
+        case ENOENT: return WASI_ENOENT;
+        case ENOSPC: return WASI_ENOSPC;
+        case EPIPE: return WASI_EPIPE;
+        case ESPIPE: return WASI_ESPIPE;
+        default: return WASI_EIO;
+    }
+}
+
+static int in_bounds(u32 memory_size, u32 offset, u64 length) {
+    return (u64) offset + length <= memory_size;
+}
+
+// Wasm is little endian, as are all our targets, so a memcpy is enough (and avoids unaligned access)
+static void store_u32(u8* p, u32 v) { memcpy(p, &v, sizeof(v)); }
+static void store_u64(u8* p, u64 v) { memcpy(p, &v, sizeof(v)); }
+static u32 load_u32(const u8* p) { u32 v; memcpy(&v, p, sizeof(v)); return v; }
+
+// The program arguments handed to the module, runtime_main sets them, any other embedder has to before running it
+static int wasi_argc = 0;
+static char** wasi_argv = NULL;
+
+void wasi_set_args(int argc, char** argv) {
+    wasi_argc = argc;
+    wasi_argv = argv;
+}
+
+u32 wasi_args_sizes_get(u8* memory, u32 memory_size, u8* argc, u8* argv_buf_size) {
+    u32 buf_size = 0;
+    for (int i = 0; i < wasi_argc; i++) {
+        buf_size += strlen(wasi_argv[i]) + 1;
+    }
+    store_u32(argc, wasi_argc);
+    store_u32(argv_buf_size, buf_size);
+    return WASI_ESUCCESS;
+}
+
+u32 wasi_args_get(u8* memory, u32 memory_size, u32 argv, u32 argv_buf) {
+    if (!in_bounds(memory_size, argv, (u64) wasi_argc * sizeof(u32))) {
+        return WASI_EFAULT;
+    }
+    u32 offset = argv_buf;
+    for (int i = 0; i < wasi_argc; i++) {
+        u32 len = strlen(wasi_argv[i]) + 1;
+        if (!in_bounds(memory_size, offset, len)) {
+            return WASI_EFAULT;
+        }
+        memcpy(memory + offset, wasi_argv[i], len);
+        store_u32(memory + argv + i * sizeof(u32), offset);
+        offset += len;
+    }
+    return WASI_ESUCCESS;
+}
+
+// We never pass the environment through to the sandbox
+u32 wasi_environ_sizes_get(u8* memory, u32 memory_size, u8* environc, u8* environ_buf_size) {
+    store_u32(environc, 0);
+    store_u32(environ_buf_size, 0);
+    return WASI_ESUCCESS;
+}
+
+u32 wasi_environ_get(u8* memory, u32 memory_size, u32 environ, u32 environ_buf) {
+    return WASI_ESUCCESS;
+}
+
+static int wasi_clock(i32 id, clockid_t* clock) {
+    switch (id) {
+        case 0: *clock = CLOCK_REALTIME; return 1;
+        case 1: *clock = CLOCK_MONOTONIC; return 1;
+        case 2: *clock = CLOCK_PROCESS_CPUTIME_ID; return 1;
+        case 3: *clock = CLOCK_THREAD_CPUTIME_ID; return 1;
+        default: return 0;
+    }
+}
+
+u32 wasi_clock_res_get(u8* memory, u32 memory_size, i32 id, u8* resolution) {
+    clockid_t clock;
+    struct timespec ts;
+    if (!wasi_clock(id, &clock)) {
+        return WASI_EINVAL;
+    }
+    if (clock_getres(clock, &ts) == -1) {
+        return wasi_errno(errno);
+    }
+    store_u64(resolution, (u64) ts.tv_sec * 1000000000 + ts.tv_nsec);
+    return WASI_ESUCCESS;
+}
+
+u32 wasi_clock_time_get(u8* memory, u32 memory_size, i32 id, i64 precision, u8* time) {
+    clockid_t clock;
+    struct timespec ts;
+    if (!wasi_clock(id, &clock)) {
+        return WASI_EINVAL;
+    }
+    if (clock_gettime(clock, &ts) == -1) {
+        return wasi_errno(errno);
+    }
+    store_u64(time, (u64) ts.tv_sec * 1000000000 + ts.tv_nsec);
+    return WASI_ESUCCESS;
+}
+
+u32 wasi_fd_close(u8* memory, u32 memory_size, i32 fd) {
+    if (close(fd) == -1) {
+        return wasi_errno(errno);
+    }
+    return WASI_ESUCCESS;
+}
+
+// struct fdstat { u8 filetype; u16 flags; u64 rights_base; u64 rights_inheriting; }
+u32 wasi_fd_fdstat_get(u8* memory, u32 memory_size, i32 fd, u8* fdstat) {
+    struct stat st;
+    if (fstat(fd, &st) == -1) {
+        return wasi_errno(errno);
+    }
+
+    u8 filetype = WASI_FILETYPE_UNKNOWN;
+    if (S_ISREG(st.st_mode)) filetype = WASI_FILETYPE_REGULAR_FILE;
+    else if (S_ISDIR(st.st_mode)) filetype = WASI_FILETYPE_DIRECTORY;
+    else if (S_ISCHR(st.st_mode)) filetype = WASI_FILETYPE_CHARACTER_DEVICE;
+    else if (S_ISBLK(st.st_mode)) filetype = WASI_FILETYPE_BLOCK_DEVICE;
+
+    memset(fdstat, 0, 24);
+    fdstat[0] = filetype;
+    // Every right, the host's own permissions are what actually constrain the sandbox
+    store_u64(fdstat + 8, UINT64_MAX);
+    store_u64(fdstat + 16, UINT64_MAX);
+    return WASI_ESUCCESS;
+}
+
+// There are no preopened directories, so there is never a prestat to report
+u32 wasi_fd_prestat_get(u8* memory, u32 memory_size, i32 fd, u8* prestat) {
+    return WASI_EBADF;
+}
+
+u32 wasi_fd_prestat_dir_name(u8* memory, u32 memory_size, i32 fd, u8* path, u32 path_len) {
+    return WASI_EBADF;
+}
+
+// iovecs are { u32 buf; u32 buf_len; } pairs of offsets into linear memory
+u32 wasi_fd_read(u8* memory, u32 memory_size, i32 fd, u8* iovs, u32 iovs_len, u8* nread) {
+    u32 total = 0;
+    for (u32 i = 0; i < iovs_len; i++) {
+        u32 buf = load_u32(iovs + i * 8);
+        u32 buf_len = load_u32(iovs + i * 8 + 4);
+        if (!in_bounds(memory_size, buf, buf_len)) {
+            return WASI_EFAULT;
+        }
+        ssize_t res = read(fd, memory + buf, buf_len);
+        if (res == -1) {
+            return wasi_errno(errno);
+        }
+        total += res;
+        if ((u32) res < buf_len) {
+            break;
+        }
+    }
+    store_u32(nread, total);
+    return WASI_ESUCCESS;
+}
+
+u32 wasi_fd_write(u8* memory, u32 memory_size, i32 fd, u8* iovs, u32 iovs_len, u8* nwritten) {
+    u32 total = 0;
+    for (u32 i = 0; i < iovs_len; i++) {
+        u32 buf = load_u32(iovs + i * 8);
+        u32 buf_len = load_u32(iovs + i * 8 + 4);
+        if (!in_bounds(memory_size, buf, buf_len)) {
+            return WASI_EFAULT;
+        }
+        ssize_t res = write(fd, memory + buf, buf_len);
+        if (res == -1) {
+            return wasi_errno(errno);
+        }
+        total += res;
+        if ((u32) res < buf_len) {
+            break;
+        }
+    }
+    store_u32(nwritten, total);
+    return WASI_ESUCCESS;
+}
+
+u32 wasi_fd_seek(u8* memory, u32 memory_size, i32 fd, i64 offset, i32 whence, u8* newoffset) {
+    int host_whence;
+    switch (whence) {
+        case 0: host_whence = SEEK_SET; break;
+        case 1: host_whence = SEEK_CUR; break;
+        case 2: host_whence = SEEK_END; break;
+        default: return WASI_EINVAL;
+    }
+    off_t res = lseek(fd, offset, host_whence);
+    if (res == -1) {
+        return wasi_errno(errno);
+    }
+    store_u64(newoffset, res);
+    return WASI_ESUCCESS;
+}
+
+void wasi_proc_exit(u8* memory, u32 memory_size, i32 code) {
+    exit(code);
+}
+
+u32 wasi_random_get(u8* memory, u32 memory_size, u8* buf, u32 buf_len) {
+    // Guests seed hash maps and generate keys from this, so it has to come from the OS
+    // getentropy hands out at most 256 bytes at a time
+    while (buf_len > 0) {
+        u32 chunk = buf_len < 256 ? buf_len : 256;
+        if (getentropy(buf, chunk) != 0) {
+            return wasi_errno(errno);
+        }
+        buf += chunk;
+        buf_len -= chunk;
+    }
+    return WASI_ESUCCESS;
+}
+
+u32 wasi_sched_yield(u8* memory, u32 memory_size) {
+    sched_yield();
+    return WASI_ESUCCESS;
+}
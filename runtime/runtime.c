@@ -185,6 +185,8 @@ INLINE double f64_floor(double a) {
 
 // We want to have some allocation logic
 WEAK u32 wasmg___heap_base = 0;
+// With --runtime-globals __heap_base is a runtime global instead, and the module says which
+WEAK const i32 wasm_heap_base_index = -1;
 u32 runtime_heap_base;
 
 u32 allocate_n_bytes(u32 n) {
@@ -228,26 +230,117 @@ void* allocate_n_bytes_ptr(u32 n) {
 // If we are using runtime globals, we need to populate them
 WEAK void populate_globals() {}
 
+// Only does anything when libc/wasi_backing.c is linked in for --wasi, which overrides this
+WEAK void wasi_set_args(int argc, char** argv) {}
+
+// Only generated for a memory image split across sections, anything else is a single block
+WEAK i32 wasm_memory_image_contiguous() { return 1; }
+
+// Only used with --stack-limit-bytes, whose modules define the real budget
+WEAK const u32 wasm_stack_limit_bytes = 0;
+WEAK WASM_TLS void* wasm_stack_limit = 0;
+
+// Called from the frame wasm will run below, so the budget is measured from there
+void wasm_set_stack_limit() {
+    char* frame = __builtin_frame_address(0);
+    if (wasm_stack_limit_bytes != 0) {
+        wasm_stack_limit = frame - wasm_stack_limit_bytes;
+    }
+}
+
+// Only used with --fuel, whose modules define the real counter and accessors
+WEAK const i64 wasm_initial_fuel = 0;
+WEAK void wasm_fuel_set(i64 fuel) {}
+WEAK void wasm_out_of_fuel() { silverfish_assert("wasm_out_of_fuel" == 0); }
+
+// Only used with --interruptible
+volatile uptr wasm_interrupt_epoch = 0;
+uptr wasm_epoch_deadline = (uptr) -1;
+
+void wasm_increment_epoch() {
+    __atomic_fetch_add(&wasm_interrupt_epoch, 1, __ATOMIC_RELAXED);
+}
+
+WEAK void wasm_epoch_expired() { silverfish_assert("wasm_epoch_expired" == 0); }
+
+// Backing store for --runtime-globals, indexed by wasm global index
+// Every value fits in 64 bits, floats are stored by their bit pattern
+u64 runtime_globals[RUNTIME_GLOBALS_MAX];
+
+i32 get_global_i32(u32 idx) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    return (i32) runtime_globals[idx];
+}
+
+void set_global_i32(u32 idx, i32 v) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    runtime_globals[idx] = (u32) v;
+}
+
+i64 get_global_i64(u32 idx) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    return (i64) runtime_globals[idx];
+}
+
+void set_global_i64(u32 idx, i64 v) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    runtime_globals[idx] = (u64) v;
+}
+
+float get_global_f32(u32 idx) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    u32 bits = (u32) runtime_globals[idx];
+    float v;
+    memcpy(&v, &bits, sizeof(v));
+    return v;
+}
+
+void set_global_f32(u32 idx, float v) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    u32 bits;
+    memcpy(&bits, &v, sizeof(bits));
+    runtime_globals[idx] = bits;
+}
+
+double get_global_f64(u32 idx) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    double v;
+    memcpy(&v, &runtime_globals[idx], sizeof(v));
+    return v;
+}
+
+void set_global_f64(u32 idx, double v) {
+    silverfish_assert(idx < RUNTIME_GLOBALS_MAX);
+    memcpy(&runtime_globals[idx], &v, sizeof(v));
+}
+
 // Code that actually runs the wasm code
 IMPORT i32 wasmf_main(i32 a, i32 b);
 
 int runtime_main(int argc, char** argv) {
     // Setup the linear memory and function table
+    silverfish_assert(wasm_memory_image_contiguous());
+    wasm_set_stack_limit();
     alloc_linear_memory();
     populate_table();
 
-    // Setup our allocation logic
-    runtime_heap_base = wasmg___heap_base;
-    printf("starting rhb %d\n", runtime_heap_base);
-    if (runtime_heap_base == 0) {
-        runtime_heap_base = memory_size;
-    }
-
     // Setup the global values (if needed), and populate the linear memory
     switch_out_of_runtime();
     populate_globals();
     switch_into_runtime();
     populate_memory();
+    wasm_fuel_set(wasm_initial_fuel);
+
+    // Setup our allocation logic, after populate_globals in case __heap_base is a runtime global
+    if (wasm_heap_base_index >= 0) {
+        runtime_heap_base = get_global_i32(wasm_heap_base_index);
+    } else {
+        runtime_heap_base = wasmg___heap_base;
+    }
+    printf("starting rhb %d\n", runtime_heap_base);
+    if (runtime_heap_base == 0) {
+        runtime_heap_base = memory_size;
+    }
 
     // In the case of a real timeout being compiled in, handle that
 //    if (wasm_execution_timeout_ms) {
@@ -272,6 +365,7 @@ int runtime_main(int argc, char** argv) {
     }
 
     stub_init();
+    wasi_set_args(argc, argv);
 
     switch_out_of_runtime();
     int ret = wasmf_main(argc, array_offset);
@@ -24,6 +24,7 @@ typedef int32_t i32;
 typedef uint32_t u32;
 typedef int64_t i64;
 typedef uint64_t u64;
+typedef uintptr_t uptr;
 #else
 // FIXME: Cortex-m specific hack
 typedef signed short i16;
@@ -32,6 +33,7 @@ typedef signed int i32;
 typedef unsigned int u32;
 typedef signed long long i64;
 typedef unsigned long long u64;
+typedef unsigned int uptr;
 #endif
 
 #if __has_include("string.h") && __has_include("math.h") && __has_include("stdio.h") && __has_include("stdlib.h")
@@ -83,6 +85,57 @@ float truncf(float x);
 extern u32 starting_pages;
 extern u32 max_pages;
 
+// Build the runtime with -DWASM_THREAD_LOCAL for modules compiled with --thread-local
+#ifdef WASM_THREAD_LOCAL
+#define WASM_TLS _Thread_local
+#else
+#define WASM_TLS
+#endif
+
+// Only present when compiled with --max-call-depth / --stack-limit-bytes
+// After catching a trap, the runtime must zero wasm_call_depth before calling back into wasm
+// wasm_set_stack_limit allows wasm_stack_limit_bytes of stack below its caller, runtime_main calls it,
+// and with --thread-local every other thread running wasm must call it too
+extern WASM_TLS u32 wasm_call_depth;
+extern WASM_TLS void* wasm_stack_limit;
+extern const u32 wasm_stack_limit_bytes;
+void wasm_set_stack_limit();
+
+// Only present when compiled with --fuel
+// wasm_out_of_fuel is called when the counter goes negative, it may refill it and return, or abort execution
+// The runtime's default traps, and runtime_main starts the counter at wasm_initial_fuel (--initial-fuel)
+extern const i64 wasm_initial_fuel;
+i64 wasm_fuel_get();
+void wasm_fuel_set(i64 fuel);
+void wasm_out_of_fuel();
+
+// Only used when compiled with --interruptible
+// A timer calls wasm_increment_epoch, and wasm_epoch_expired is called once the epoch reaches wasm_epoch_deadline
+// Both are pointer sized, so compiled code reads the epoch in one access even on 32 bit targets
+// The runtime's wasm_epoch_expired traps, and the deadline starts out never reached
+extern volatile uptr wasm_interrupt_epoch;
+extern uptr wasm_epoch_deadline;
+void wasm_increment_epoch();
+void wasm_epoch_expired();
+
+// Only used when compiled with --runtime-globals
+// populate_globals sets the module's own globals, imported ones must be set by the host before running
+// The whole array can be copied out and back in to snapshot the module's globals
+#define RUNTIME_GLOBALS_MAX 1024
+extern u64 runtime_globals[RUNTIME_GLOBALS_MAX];
+void populate_globals();
+i32 get_global_i32(u32 idx);
+void set_global_i32(u32 idx, i32 v);
+i64 get_global_i64(u32 idx);
+void set_global_i64(u32 idx, i64 v);
+float get_global_f32(u32 idx);
+void set_global_f32(u32 idx, float v);
+double get_global_f64(u32 idx);
+void set_global_f64(u32 idx, double v);
+
+// The program arguments WASI's args_get reports, runtime_main passes its own, other embedders must call this
+void wasi_set_args(int argc, char** argv);
+
 // Some backends might need to do manual switching when we go into the runtime
 INLINE void switch_into_runtime();
 INLINE void switch_out_of_runtime();
@@ -90,6 +143,8 @@ INLINE void switch_out_of_runtime();
 // The code generator also compiles in stubs that populate the linear memory and function table
 void populate_memory();
 void populate_table();
+// With --memory-image-section, whether the linker placed the zeroed tail right after the image
+i32 wasm_memory_image_contiguous();
 
 // memory/* provides these memory functions
 extern void* memory;
@@ -0,0 +1,299 @@
+use serde::Serialize;
+
+use wasmparser::FuncType;
+use wasmparser::Type;
+
+use crate::codegen::fuel::FUEL_GET;
+use crate::codegen::fuel::FUEL_SET;
+use crate::codegen::globals::POPULATE_GLOBALS;
+use crate::codegen::imports::native_import_symbol;
+use crate::codegen::imports::ResolvedImport;
+use crate::codegen::memory::MEMORY_ACCESSOR;
+use crate::codegen::memory::MEMORY_SIZE_ACCESSOR;
+use crate::codegen::symbols::defined_symbol;
+use crate::codegen::thread_local::THREAD_INIT;
+use crate::codegen::thread_local::THREAD_TEARDOWN;
+use crate::codegen::vmctx::INSTANCE_INIT;
+use crate::codegen::vmctx::INSTANCE_SIZE;
+use crate::codegen::visibility::Visibility;
+use crate::wasm::Export;
+use crate::wasm::Function;
+use crate::wasm::Global;
+use crate::wasm::WasmModule;
+use crate::Opt;
+
+// The types that appear at the native boundary of a compiled module
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AbiType {
+    I32,
+    I64,
+    F32,
+    F64,
+    U32,
+    // A pointer into linear memory
+    Bytes,
+    // A pointer to a `struct wasm_instance`, with --vmctx
+    Instance,
+}
+
+fn abi_type(t: Type) -> AbiType {
+    match t {
+        Type::I32 => AbiType::I32,
+        Type::I64 => AbiType::I64,
+        Type::F32 => AbiType::F32,
+        Type::F64 => AbiType::F64,
+        e => panic!("wasm type {:?} does not have a native representation", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AbiFunction {
+    pub name: String,
+    pub symbol: String,
+    pub params: Vec<AbiType>,
+    pub result: Option<AbiType>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AbiImport {
+    pub module: String,
+    pub field: String,
+    pub symbol: String,
+    pub params: Vec<AbiType>,
+    pub result: Option<AbiType>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AbiGlobal {
+    pub name: String,
+    // None when the global has no symbol, because it was inlined or lives in the instance
+    pub symbol: Option<String>,
+    pub value_type: AbiType,
+    pub mutable: bool,
+    pub thread_local: bool,
+    // The index to use with the runtime's get and set stubs, with --runtime-globals
+    pub runtime_index: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AbiImportedGlobal {
+    pub module: String,
+    pub field: String,
+    pub symbol: String,
+    pub value_type: AbiType,
+    pub mutable: bool,
+    pub runtime_index: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AbiMemory {
+    pub initial_pages: u32,
+    pub maximum_pages: Option<u32>,
+}
+
+// Everything the host needs to know to link against and drive a compiled module
+// Built from the same WasmModule and options codegen uses, so generated headers and bindings cannot drift
+#[derive(Debug, Serialize)]
+pub struct ModuleAbi {
+    pub source_name: String,
+    pub vmctx: bool,
+    pub thread_local: bool,
+    pub exports: Vec<AbiFunction>,
+    pub imports: Vec<AbiImport>,
+    pub globals: Vec<AbiGlobal>,
+    pub imported_globals: Vec<AbiImportedGlobal>,
+    pub memory: Option<AbiMemory>,
+    pub table_size: Option<u32>,
+    pub entry_points: Vec<AbiFunction>,
+}
+
+fn native_params(opt: &Opt, ty: &FuncType) -> Vec<AbiType> {
+    let instance = if opt.vmctx { Some(AbiType::Instance) } else { None };
+    instance.into_iter().chain(ty.params.iter().map(|t| abi_type(*t))).collect()
+}
+
+fn native_result(ty: &FuncType) -> Option<AbiType> {
+    ty.returns.first().map(|t| abi_type(*t))
+}
+
+// What the native symbol behind an imported function is called with
+pub fn import_native_signature(
+    opt: &Opt,
+    ty: &FuncType,
+    resolved: Option<&ResolvedImport>,
+) -> (Vec<AbiType>, Option<AbiType>) {
+    // Imports bound with pass_memory get the memory base instead of the instance
+    let params = match resolved {
+        Some(ResolvedImport::PassMemory { .. }) => {
+            let wasm_params = ty.params.iter().map(|t| abi_type(*t));
+            Some(AbiType::Bytes).into_iter().chain(wasm_params).collect()
+        }
+        Some(ResolvedImport::Lowered { call }) => call.native_params(),
+        _ => native_params(opt, ty),
+    };
+    (params, native_result(ty))
+}
+
+fn entry_point(opt: &Opt, name: &str, params: Vec<AbiType>, result: Option<AbiType>) -> AbiFunction {
+    AbiFunction {
+        name: name.to_string(),
+        symbol: defined_symbol(opt, name),
+        params,
+        result,
+    }
+}
+
+pub fn describe_module(
+    opt: &Opt,
+    wasm_module: &WasmModule,
+    visibility: &Visibility,
+    resolved_imports: Option<&[Option<ResolvedImport>]>,
+) -> ModuleAbi {
+    let mut exports = Vec::new();
+    let mut globals = Vec::new();
+    for e in &wasm_module.exports {
+        match e {
+            // Exports --export-only dropped are internal, or not even compiled, so the host cannot reach them
+            Export::Function { index, .. } if !visibility.public[*index] => {}
+            Export::Global { index, .. } if !visibility.public_globals[*index] => {}
+            Export::Function { index, name } => {
+                let f = &wasm_module.functions[*index];
+                exports.push(AbiFunction {
+                    name: name.clone(),
+                    symbol: defined_symbol(opt, f.get_name()),
+                    params: native_params(opt, f.get_type()),
+                    result: native_result(f.get_type()),
+                });
+            }
+            Export::Global { index, name } => {
+                if let Global::InModule {
+                    generated_name,
+                    content_type,
+                    mutable,
+                    ..
+                } = &wasm_module.globals[*index]
+                {
+                    let inlined = !*mutable && opt.inline_constant_globals;
+                    let has_symbol = !opt.vmctx && !opt.use_runtime_global_handling && !inlined;
+                    // Inlined or not, populate_globals fills in the global's runtime slot
+                    let in_runtime = opt.use_runtime_global_handling;
+                    globals.push(AbiGlobal {
+                        name: name.clone(),
+                        symbol: if has_symbol { Some(defined_symbol(opt, generated_name)) } else { None },
+                        value_type: abi_type(*content_type),
+                        mutable: *mutable,
+                        // Constants are never made thread local, see mark_thread_local
+                        thread_local: opt.thread_local && *mutable,
+                        runtime_index: if in_runtime { Some(*index as u32) } else { None },
+                    });
+                }
+            }
+            Export::Memory { .. } => {}
+        }
+    }
+
+    let mut imports = Vec::new();
+    for (n, f) in wasm_module.functions.iter().enumerate() {
+        if let Function::Imported {
+            source,
+            name,
+            appended,
+            ty,
+            ..
+        } = f
+        {
+            let resolved = resolved_imports.and_then(|r| r[n].as_ref());
+            let symbol = match native_import_symbol(appended, resolved) {
+                Some(symbol) => symbol.to_string(),
+                None => continue,
+            };
+            let (params, result) = import_native_signature(opt, ty, resolved);
+            imports.push(AbiImport {
+                module: source.clone(),
+                field: name.clone(),
+                symbol,
+                params,
+                result,
+            });
+        }
+    }
+
+    let imported_globals = wasm_module
+        .globals
+        .iter()
+        .enumerate()
+        .filter_map(|(n, g)| match g {
+            Global::Imported {
+                source,
+                field,
+                name,
+                content_type,
+                mutable,
+            } => Some(AbiImportedGlobal {
+                module: source.clone(),
+                field: field.clone(),
+                symbol: name.clone(),
+                value_type: abi_type(*content_type),
+                mutable: *mutable,
+                runtime_index: if opt.use_runtime_global_handling { Some(n as u32) } else { None },
+            }),
+            _ => None,
+        })
+        .collect();
+
+    let memory = wasm_module.memories.first().map(|m| AbiMemory {
+        initial_pages: m.limits.initial,
+        maximum_pages: m.limits.maximum,
+    });
+    let table_size = wasm_module.tables.first().map(|t| t.limits.initial);
+
+    let instance: Vec<AbiType> = if opt.vmctx { vec![AbiType::Instance] } else { Vec::new() };
+    let mut entry_points = Vec::new();
+    if opt.vmctx {
+        entry_points.push(entry_point(opt, INSTANCE_SIZE, vec![], Some(AbiType::U32)));
+        entry_points.push(entry_point(
+            opt,
+            INSTANCE_INIT,
+            vec![AbiType::Instance, AbiType::Bytes, AbiType::U32],
+            None,
+        ));
+    }
+    if opt.thread_local {
+        entry_points.push(entry_point(opt, THREAD_INIT, vec![], None));
+        entry_points.push(entry_point(opt, THREAD_TEARDOWN, vec![], None));
+    }
+    if table_size.is_some() {
+        entry_points.push(entry_point(opt, "populate_table", vec![], None));
+    }
+    if opt.use_runtime_global_handling {
+        entry_points.push(entry_point(opt, POPULATE_GLOBALS, vec![], None));
+    }
+    if let Some(start) = wasm_module.start_function {
+        let f = &wasm_module.functions[start as usize];
+        let mut start_entry = entry_point(opt, "start", native_params(opt, f.get_type()), None);
+        start_entry.symbol = defined_symbol(opt, f.get_name());
+        entry_points.push(start_entry);
+    }
+    if memory.is_some() {
+        entry_points.push(entry_point(opt, MEMORY_ACCESSOR, instance.clone(), Some(AbiType::Bytes)));
+        entry_points.push(entry_point(opt, MEMORY_SIZE_ACCESSOR, instance, Some(AbiType::U32)));
+    }
+    if opt.fuel {
+        entry_points.push(entry_point(opt, FUEL_GET, vec![], Some(AbiType::I64)));
+        entry_points.push(entry_point(opt, FUEL_SET, vec![AbiType::I64], None));
+    }
+
+    ModuleAbi {
+        source_name: wasm_module.source_name.clone(),
+        vmctx: opt.vmctx,
+        thread_local: opt.thread_local,
+        exports,
+        imports,
+        globals,
+        imported_globals,
+        memory,
+        table_size,
+        entry_points,
+    }
+}
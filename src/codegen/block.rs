@@ -1,8 +1,13 @@
+use std::os::raw::c_char;
+
+use llvm::ffi::LLVMRealPredicate;
 use llvm::BasicBlock;
 use llvm::Builder;
 use llvm::Compile;
+use llvm::Context as LLVMCtx;
 use llvm::PointerType;
 use llvm::Predicate;
+use llvm::Sub;
 use llvm::Value;
 
 use wasmparser::Type;
@@ -12,12 +17,22 @@ use crate::codegen::ModuleCtx;
 use crate::codegen::breakout::BreakoutTarget;
 use crate::codegen::breakout::WBreakoutTarget;
 
+use crate::codegen::devirtualize::build_devirtualized_call;
+use crate::codegen::fuel::build_fuel_check;
 use crate::codegen::function::FunctionCtx;
+use crate::codegen::interrupt::build_interrupt_check;
 
 use crate::codegen::runtime_stubs::*;
 
+use crate::codegen::stack_guard::build_stack_guard_epilogue;
+
+use crate::codegen::table::build_inline_table_fetch;
+
 use crate::codegen::type_conversions::llvm_type_to_wasm_type;
-use crate::codegen::type_conversions::wasm_func_type_to_llvm_type;
+use crate::codegen::type_conversions::wasm_func_type_to_instance_llvm_type;
+use crate::codegen::vmctx::build_instance_field_ptr;
+use crate::codegen::vmctx::MEMORY_BASE_FIELD;
+use crate::codegen::type_conversions::wasm_type_to_llvm_type;
 
 use crate::wasm::Instruction;
 
@@ -45,6 +60,7 @@ pub fn compile_block<'a, 'b>(
     // A single wasm block might need multiple llvm basic blocks to be expressed
     let mut basic_block = initial_bb;
     b.position_at_end(basic_block);
+    basic_block = build_fuel_check(m_ctx, f_ctx, b, basic_block, instructions);
 
     // A block can be terminated, which changes the behavior of the "end" instruction
     let mut block_terminated = false;
@@ -55,6 +71,9 @@ pub fn compile_block<'a, 'b>(
         remaining_instructions = &remaining_instructions[1..];
         info!("Parsing instr {:?}", inst);
 
+        // In deterministic mode any NaN an instruction creates is replaced by the canonical one after the fact
+        let canonicalize_result = m_ctx.opt.deterministic && may_produce_nan(&inst);
+
         match inst {
             Instruction::BlockStart { produced_type } => {
                 // The inner block must have a basic block to hold its code
@@ -103,6 +122,8 @@ pub fn compile_block<'a, 'b>(
                 if let Some(result) = result {
                     stack.push(result)
                 }
+
+                basic_block = build_fuel_check(m_ctx, f_ctx, b, basic_block, remaining_instructions);
             },
             Instruction::LoopStart { produced_type } => {
                 // The inner loop must have a basic block to hold its code
@@ -119,6 +140,9 @@ pub fn compile_block<'a, 'b>(
                 // We need to do phi instruction modification, so we need to keep the phi vector. Thus we pass in a copy
                 let inner_locals = inner_local_phis.clone();
 
+                // Every back edge comes through the loop header, so checking here bounds how long a loop can run
+                let loop_body_bb = build_interrupt_check(m_ctx, f_ctx, b, inner_bb);
+
                 // The inner jump invalidates our old basic block, so we need a new one
                 let after_bb = f_ctx.generate_block();
 
@@ -135,7 +159,7 @@ pub fn compile_block<'a, 'b>(
                     breakout_stack,
                     &loop_termination_target.clone(),
                     inner_locals,
-                    inner_bb,
+                    loop_body_bb,
                     remaining_instructions,
                 );
 
@@ -160,6 +184,8 @@ pub fn compile_block<'a, 'b>(
                 if let Some(result) = result {
                     stack.push(result)
                 }
+
+                basic_block = build_fuel_check(m_ctx, f_ctx, b, basic_block, remaining_instructions);
             },
             Instruction::End => {
                 if !block_terminated {
@@ -190,6 +216,7 @@ pub fn compile_block<'a, 'b>(
 
                 basic_block = else_block;
                 b.position_at_end(basic_block);
+                basic_block = build_fuel_check(m_ctx, f_ctx, b, basic_block, remaining_instructions);
             },
             Instruction::BrTable { table, default } => {
                 let switch_value = stack.pop().unwrap();
@@ -221,6 +248,7 @@ pub fn compile_block<'a, 'b>(
             }
 ,
             Instruction::Return => {
+                build_stack_guard_epilogue(m_ctx, b);
                 if f_ctx.has_return {
                     b.build_ret(stack.pop().unwrap());
                 } else {
@@ -250,10 +278,12 @@ pub fn compile_block<'a, 'b>(
                 // args are pushed in the opposite order of their use
                 args.reverse();
 
-                let result = b.build_call(llvm_f, &args);
+                let result = b.build_call(llvm_f, &f_ctx.call_args(&args));
                 if wasm_f.has_return() {
                     stack.push(result);
                 }
+
+                basic_block = build_fuel_check(m_ctx, f_ctx, b, basic_block, remaining_instructions);
             },
             Instruction::CallIndirect { type_index } => {
                 let table_index = stack.pop().unwrap();
@@ -277,19 +307,44 @@ pub fn compile_block<'a, 'b>(
                 // args are pushed in the opposite order of their use
                 args.reverse();
 
+                if let Some((result, bb)) = build_devirtualized_call(
+                    m_ctx, f_ctx, b, basic_block, table_index, type_index, &args,
+                ) {
+                    basic_block = bb;
+                    if let Some(result) = result {
+                        stack.push(result);
+                    }
+                    basic_block = build_fuel_check(m_ctx, f_ctx, b, basic_block, remaining_instructions);
+                    continue;
+                }
+
                 // Fetch the func pointer from our table
-                let f_ptr_as_void = b.build_call(
-                    get_stub_function(m_ctx, TABLE_FETCH),
-                    &[table_index, type_index.compile(m_ctx.llvm_ctx)],
-                );
+                let f_ptr_as_void = match m_ctx.table {
+                    Some(table) => {
+                        let (f_ptr, bb) =
+                            build_inline_table_fetch(m_ctx, f_ctx, b, table, table_index, type_index);
+                        basic_block = bb;
+                        f_ptr
+                    }
+                    None => b.build_call(
+                        get_stub_function(m_ctx, TABLE_FETCH),
+                        &[table_index, m_ctx.canonical_type_id(type_index).compile(m_ctx.llvm_ctx)],
+                    ),
+                };
                 // Then cast it from a void pointer to a function pointer
-                let f_type = PointerType::new(wasm_func_type_to_llvm_type(m_ctx.llvm_ctx, f_type));
+                let f_type = PointerType::new(wasm_func_type_to_instance_llvm_type(
+                    m_ctx.llvm_ctx,
+                    f_type,
+                    m_ctx.instance_type,
+                ));
                 let f_ptr = b.build_bit_cast(f_ptr_as_void, f_type);
 
-                let result = b.build_value_call(f_ptr, &args);
+                let result = b.build_value_call(f_ptr, &f_ctx.call_args(&args));
                 if has_return {
                     stack.push(result);
                 }
+
+                basic_block = build_fuel_check(m_ctx, f_ctx, b, basic_block, remaining_instructions);
             },
             Instruction::Drop => {
                 stack.pop().unwrap();
@@ -321,12 +376,12 @@ pub fn compile_block<'a, 'b>(
             },
 
             Instruction::GetGlobal { index } => {
-                let v = m_ctx.globals[index as usize].load(m_ctx, b);
+                let v = m_ctx.globals[index as usize].load(m_ctx, f_ctx, b);
                 stack.push(v);
             },
             Instruction::SetGlobal { index } => {
                 let v = stack.pop().unwrap();
-                m_ctx.globals[index as usize].store(m_ctx, b, v);
+                m_ctx.globals[index as usize].store(m_ctx, f_ctx, b, v);
             }
 ,
             Instruction::I32Const(i) => {
@@ -359,32 +414,24 @@ pub fn compile_block<'a, 'b>(
                 stack.push(result);
             },
             Instruction::I32TruncSF32 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F32);
-                //let result = if m_ctx.opt.use_fast_unsafe_implementations {
-                let result = b.build_fptosi(v, <i32>::get_type(m_ctx.llvm_ctx));
-                //} else {
-                //    b.build_call(get_stub_function(m_ctx, I32_TRUNC_F32), &[v])
-                //};
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F32, Type::I32, true,
+                );
             },
             Instruction::I32TruncUF32 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F32);
-                let result = b.build_fptoui(v, <u32>::get_type(m_ctx.llvm_ctx));
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F32, Type::I32, false,
+                );
             },
             Instruction::I32TruncSF64 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F64);
-                let result = b.build_fptosi(v, <i32>::get_type(m_ctx.llvm_ctx));
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F64, Type::I32, true,
+                );
             },
             Instruction::I32TruncUF64 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F64);
-                let result = b.build_fptoui(v, <u32>::get_type(m_ctx.llvm_ctx));
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F64, Type::I32, false,
+                );
             },
 
             Instruction::I32Add => {
@@ -587,37 +634,24 @@ pub fn compile_block<'a, 'b>(
             },
 
             Instruction::I64TruncSF32 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F32);
-                let result = b.build_bit_cast(v, 
-                    <i32>::get_type(m_ctx.llvm_ctx));
-
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F32, Type::I64, true,
+                );
             }
             Instruction::I64TruncUF32 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F32);
-                let result = b.build_bit_cast(v, 
-                    <i64>::get_type(m_ctx.llvm_ctx));
-                //let result = b.build_call(get_stub_function(m_ctx, U64_TRUNC_F32), &[v]);
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F32, Type::I64, false,
+                );
             }
             Instruction::I64TruncSF64 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F64);
-
-                let result = b.build_bit_cast(v, 
-                    <i64>::get_type(m_ctx.llvm_ctx));
-                //let result = b.build_call(get_stub_function(m_ctx, I64_TRUNC_F64), &[v]);
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F64, Type::I64, true,
+                );
             }
             Instruction::I64TruncUF64 => {
-                let v = stack.pop().unwrap();
-                assert_type(m_ctx, v, Type::F64);
-                let result = b.build_bit_cast(v, 
-                    <i64>::get_type(m_ctx.llvm_ctx));
-                //let result = b.build_call(get_stub_function(m_ctx, U64_TRUNC_F64), &[v]);
-                stack.push(result);
+                basic_block = float_trunc_to_int(
+                    m_ctx, f_ctx, b, &mut stack, basic_block, Type::F64, Type::I64, false,
+                );
             }
 
             Instruction::I64Add => {
@@ -880,24 +914,23 @@ pub fn compile_block<'a, 'b>(
                 stack.push(result);
             }
 
-            Instruction::F32Eq => float_cmp(m_ctx, b, &mut stack, Predicate::Equal),
-            Instruction::F32Ne => float_cmp(m_ctx, b, &mut stack, Predicate::NotEqual),
-            Instruction::F32Le => float_cmp(m_ctx, b, &mut stack, Predicate::LessThanOrEqual),
-            Instruction::F32Lt => float_cmp(m_ctx, b, &mut stack, Predicate::LessThan),
-            Instruction::F32Ge => float_cmp(m_ctx, b, &mut stack, Predicate::GreaterThanOrEqual),
-            Instruction::F32Gt => float_cmp(m_ctx, b, &mut stack, Predicate::GreaterThan),
+            // Every wasm comparison except `ne` is false if either operand is NaN, so only `ne` is unordered
+            Instruction::F32Eq => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOEQ),
+            Instruction::F32Ne => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealUNE),
+            Instruction::F32Le => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOLE),
+            Instruction::F32Lt => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOLT),
+            Instruction::F32Ge => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOGE),
+            Instruction::F32Gt => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOGT),
 
             Instruction::F32Min => perform_bin_op(m_ctx, &mut stack, Type::F32, |v1, v2| {
-                let tmp1 = b.build_signed_cmp(v1, v2, Predicate::LessThan);
-                b.build_select(tmp1, v1, v2)
+                float_min_max(m_ctx, b, v1, v2, Type::F32, true)
             }),
             Instruction::F32CopySign => perform_bin_op(m_ctx, &mut stack, Type::F32, |v1, v2| {
                 b.build_call(
                     get_stub_function(m_ctx, F32_CSIGN), &[v1, v2])
             }),
             Instruction::F32Max => perform_bin_op(m_ctx, &mut stack, Type::F32, |v1, v2| {
-                let tmp1 = b.build_signed_cmp(v1, v2, Predicate::GreaterThan);
-                b.build_select(tmp1, v1, v2)
+                float_min_max(m_ctx, b, v1, v2, Type::F32, false)
             }),
             Instruction::F32Floor => {
                 let v = stack.pop().unwrap();
@@ -917,7 +950,12 @@ pub fn compile_block<'a, 'b>(
                 let v = stack.pop().unwrap();
                 assert_type(m_ctx, v, Type::F32);
 
-                let result = b.build_call(get_stub_function(m_ctx, F32_NEAREST), &[v]);
+                let nearest = if m_ctx.opt.use_fast_unsafe_implementations {
+                    F32_NEARBYINT
+                } else {
+                    F32_NEAREST
+                };
+                let result = b.build_call(get_stub_function(m_ctx, nearest), &[v]);
                 stack.push(result);
             }
 
@@ -1004,24 +1042,23 @@ pub fn compile_block<'a, 'b>(
                 stack.push(result);
             }
 
-            Instruction::F64Eq => float_cmp(m_ctx, b, &mut stack, Predicate::Equal),
-            Instruction::F64Ne => float_cmp(m_ctx, b, &mut stack, Predicate::NotEqual),
-            Instruction::F64Le => float_cmp(m_ctx, b, &mut stack, Predicate::LessThanOrEqual),
-            Instruction::F64Lt => float_cmp(m_ctx, b, &mut stack, Predicate::LessThan),
-            Instruction::F64Ge => float_cmp(m_ctx, b, &mut stack, Predicate::GreaterThanOrEqual),
-            Instruction::F64Gt => float_cmp(m_ctx, b, &mut stack, Predicate::GreaterThan),
+            // Every wasm comparison except `ne` is false if either operand is NaN, so only `ne` is unordered
+            Instruction::F64Eq => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOEQ),
+            Instruction::F64Ne => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealUNE),
+            Instruction::F64Le => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOLE),
+            Instruction::F64Lt => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOLT),
+            Instruction::F64Ge => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOGE),
+            Instruction::F64Gt => float_cmp(m_ctx, b, &mut stack, LLVMRealPredicate::LLVMRealOGT),
 
             Instruction::F64CopySign => perform_bin_op(m_ctx, &mut stack, Type::F64, |v1, v2| {
                 b.build_call(
                     get_stub_function(m_ctx, F64_CSIGN), &[v1, v2])
             }),
             Instruction::F64Min => perform_bin_op(m_ctx, &mut stack, Type::F64, |v1, v2| {
-                let tmp1 = b.build_signed_cmp(v1, v2, Predicate::LessThan);
-                b.build_select(tmp1, v1, v2)
+                float_min_max(m_ctx, b, v1, v2, Type::F64, true)
             }),
             Instruction::F64Max => perform_bin_op(m_ctx, &mut stack, Type::F64, |v1, v2| {
-                let tmp1 = b.build_signed_cmp(v1, v2, Predicate::GreaterThan);
-                b.build_select(tmp1, v1, v2)
+                float_min_max(m_ctx, b, v1, v2, Type::F64, false)
             }),
             Instruction::F64Floor => {
                 let v = stack.pop().unwrap();
@@ -1041,104 +1078,156 @@ pub fn compile_block<'a, 'b>(
                 let v = stack.pop().unwrap();
                 assert_type(m_ctx, v, Type::F64);
 
-                let result = b.build_call(get_stub_function(m_ctx, F64_NEAREST), &[v]);
+                let nearest = if m_ctx.opt.use_fast_unsafe_implementations {
+                    F64_NEARBYINT
+                } else {
+                    F64_NEAREST
+                };
+                let result = b.build_call(get_stub_function(m_ctx, nearest), &[v]);
                 stack.push(result);
             }
 
             Instruction::I32Load { offset, .. } => {
-                let v = load_val::<i32>(m_ctx, b, &mut stack, offset);
+                let v = load_val::<i32>(m_ctx, f_ctx, b, &mut stack, offset);
                 stack.push(v);
             }
             Instruction::I32Store { offset, .. } => {
                 let v = stack.pop().unwrap();
-                store_val::<i32>(m_ctx, b, &mut stack, offset, v);
+                store_val::<i32>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
             Instruction::I32Load8S { offset, .. } => {
-                load_as_i32_sext::<i8>(m_ctx, b, &mut stack, offset);
+                load_as_i32_sext::<i8>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I32Load8U { offset, .. } => {
-                load_as_i32_zext::<u8>(m_ctx, b, &mut stack, offset);
+                load_as_i32_zext::<u8>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I32Store8 { offset, .. } => {
                 let i32_v = stack.pop().unwrap();
                 let v = b.build_trunc(i32_v, <u8>::get_type(m_ctx.llvm_ctx));
-                store_val::<u8>(m_ctx, b, &mut stack, offset, v);
+                store_val::<u8>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
             Instruction::I32Load16S { offset, .. } => {
-                load_as_i32_sext::<i16>(m_ctx, b, &mut stack, offset);
+                load_as_i32_sext::<i16>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I32Load16U { offset, .. } => {
-                load_as_i32_zext::<u16>(m_ctx, b, &mut stack, offset);
+                load_as_i32_zext::<u16>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I32Store16 { offset, .. } => {
                 let i32_v = stack.pop().unwrap();
                 let v = b.build_trunc(i32_v, <u16>::get_type(m_ctx.llvm_ctx));
-                store_val::<u16>(m_ctx, b, &mut stack, offset, v);
+                store_val::<u16>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
 
             Instruction::I64Load { offset, .. } => {
-                let v = load_val::<i64>(m_ctx, b, &mut stack, offset);
+                let v = load_val::<i64>(m_ctx, f_ctx, b, &mut stack, offset);
                 stack.push(v);
             }
             Instruction::I64Store { offset, .. } => {
                 let v = stack.pop().unwrap();
-                store_val::<i64>(m_ctx, b, &mut stack, offset, v);
+                store_val::<i64>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
             Instruction::I64Load8S { offset, .. } => {
-                load_as_i64_sext::<i8>(m_ctx, b, &mut stack, offset);
+                load_as_i64_sext::<i8>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I64Load8U { offset, .. } => {
-                load_as_i64_zext::<u8>(m_ctx, b, &mut stack, offset);
+                load_as_i64_zext::<u8>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I64Store8 { offset, .. } => {
                 let i64_v = stack.pop().unwrap();
                 let v = b.build_trunc(i64_v, <u8>::get_type(m_ctx.llvm_ctx));
-                store_val::<u8>(m_ctx, b, &mut stack, offset, v);
+                store_val::<u8>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
             Instruction::I64Load16S { offset, .. } => {
-                load_as_i64_sext::<i16>(m_ctx, b, &mut stack, offset);
+                load_as_i64_sext::<i16>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I64Load16U { offset, .. } => {
-                load_as_i64_zext::<u16>(m_ctx, b, &mut stack, offset);
+                load_as_i64_zext::<u16>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I64Store16 { offset, .. } => {
                 let i64_v = stack.pop().unwrap();
                 let v = b.build_trunc(i64_v, <u16>::get_type(m_ctx.llvm_ctx));
-                store_val::<u16>(m_ctx, b, &mut stack, offset, v);
+                store_val::<u16>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
             Instruction::I64Load32S { offset, .. } => {
-                load_as_i64_sext::<i32>(m_ctx, b, &mut stack, offset);
+                load_as_i64_sext::<i32>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I64Load32U { offset, .. } => {
-                load_as_i64_zext::<u32>(m_ctx, b, &mut stack, offset);
+                load_as_i64_zext::<u32>(m_ctx, f_ctx, b, &mut stack, offset);
             }
             Instruction::I64Store32 { offset, .. } => {
                 let i64_v = stack.pop().unwrap();
                 let v = b.build_trunc(i64_v, <u32>::get_type(m_ctx.llvm_ctx));
-                store_val::<u32>(m_ctx, b, &mut stack, offset, v);
+                store_val::<u32>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
 
             Instruction::F32Load { offset, .. } => {
-                let v = load_val::<f32>(m_ctx, b, &mut stack, offset);
+                let v = load_val::<f32>(m_ctx, f_ctx, b, &mut stack, offset);
                 stack.push(v);
             }
             Instruction::F32Store { offset, .. } => {
                 let v = stack.pop().unwrap();
-                store_val::<f32>(m_ctx, b, &mut stack, offset, v);
+                store_val::<f32>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
 
             Instruction::F64Load { offset, .. } => {
-                let v = load_val::<f64>(m_ctx, b, &mut stack, offset);
+                let v = load_val::<f64>(m_ctx, f_ctx, b, &mut stack, offset);
                 stack.push(v);
             }
             Instruction::F64Store { offset, .. } => {
                 let v = stack.pop().unwrap();
-                store_val::<f64>(m_ctx, b, &mut stack, offset, v);
+                store_val::<f64>(m_ctx, f_ctx, b, &mut stack, offset, v);
             }
         }
+
+        if canonicalize_result {
+            let v = stack.pop().unwrap();
+            stack.push(canonicalize_nan(m_ctx, b, v));
+        }
     }
 }
 
+// These are the instructions where wasm leaves the NaN bit pattern of the result nondeterministic
+// Abs, neg, copysign, reinterprets, loads and constants only move bits around, so they are left alone
+fn may_produce_nan(inst: &Instruction) -> bool {
+    match inst {
+        Instruction::F32Add
+        | Instruction::F32Sub
+        | Instruction::F32Mul
+        | Instruction::F32Div
+        | Instruction::F32Sqrt
+        | Instruction::F32Min
+        | Instruction::F32Max
+        | Instruction::F32Floor
+        | Instruction::F32Ceil
+        | Instruction::F32Trunc
+        | Instruction::F32Nearest
+        | Instruction::F32DemoteF64
+        | Instruction::F64Add
+        | Instruction::F64Sub
+        | Instruction::F64Mul
+        | Instruction::F64Div
+        | Instruction::F64Sqrt
+        | Instruction::F64Min
+        | Instruction::F64Max
+        | Instruction::F64Floor
+        | Instruction::F64Ceil
+        | Instruction::F64Trunc
+        | Instruction::F64Nearest
+        | Instruction::F64PromoteF32 => true,
+        _ => false,
+    }
+}
+
+fn canonicalize_nan<'a>(m_ctx: &'a ModuleCtx, b: &'a Builder, v: &'a Value) -> &'a Value {
+    let canonical = match llvm_type_to_wasm_type(m_ctx.llvm_ctx, v.get_type()) {
+        Type::F32 => f32::from_bits(0x7fc0_0000).compile(m_ctx.llvm_ctx),
+        Type::F64 => f64::from_bits(0x7ff8_0000_0000_0000).compile(m_ctx.llvm_ctx),
+        t => panic!("cannot canonicalize a value of type {:?}", t),
+    };
+    let is_nan = build_fcmp(b, v, v, LLVMRealPredicate::LLVMRealUNO);
+    b.build_select(is_nan, canonical, v)
+}
+
 fn assert_type(m_ctx: &ModuleCtx, v: &Value, t: Type) {
     let value_type = llvm_type_to_wasm_type(m_ctx.llvm_ctx, v.get_type());
     assert_eq!(value_type, t);
@@ -1237,18 +1326,195 @@ fn i64_cmp_unsigned<'a>(
     stack.push(i32_result);
 }
 
-fn float_cmp<'a>(m_ctx: &'a ModuleCtx, b: &'a Builder, stack: &mut Vec<&'a Value>, p: Predicate) {
+// llvm_alt has no way to pick the ordered/unordered variant of a float comparison, so we go to the C api
+fn build_fcmp<'a>(b: &'a Builder, v1: &'a Value, v2: &'a Value, p: LLVMRealPredicate) -> &'a Value {
+    let no_name = b"\0".as_ptr() as *const c_char;
+    unsafe { llvm::ffi::core::LLVMBuildFCmp(b.into(), p, v1.into(), v2.into(), no_name).into() }
+}
+
+fn float_cmp<'a>(
+    m_ctx: &'a ModuleCtx,
+    b: &'a Builder,
+    stack: &mut Vec<&'a Value>,
+    p: LLVMRealPredicate,
+) {
     let v2 = stack.pop().unwrap();
     let v1 = stack.pop().unwrap();
     assert_types_match(m_ctx, v1, v2);
 
-    let result = b.build_signed_cmp(v1, v2, p);
+    let result = build_fcmp(b, v1, v2, p);
     let i32_result = b.build_zext(result, <i32>::get_type(m_ctx.llvm_ctx));
     stack.push(i32_result);
 }
 
+fn float_min_max<'a>(
+    m_ctx: &'a ModuleCtx,
+    b: &'a Builder,
+    v1: &'a Value,
+    v2: &'a Value,
+    ty: Type,
+    is_min: bool,
+) -> &'a Value {
+    let predicate = if is_min {
+        LLVMRealPredicate::LLVMRealOLT
+    } else {
+        LLVMRealPredicate::LLVMRealOGT
+    };
+    if m_ctx.opt.use_fast_unsafe_implementations {
+        // Ignores NaN propagation and the sign of zero, but is a single compare and select
+        let cmp = build_fcmp(b, v1, v2, predicate);
+        return b.build_select(cmp, v1, v2);
+    }
+
+    build_nan_propagating_min_max(m_ctx.llvm_ctx, b, v1, v2, ty, is_min)
+}
+
+// Wasm min/max, which llvm.minimum/maximum would be if older LLVMs and soft-float targets could lower them
+fn build_nan_propagating_min_max<'a>(
+    ctx: &'a LLVMCtx,
+    b: &'a Builder,
+    v1: &'a Value,
+    v2: &'a Value,
+    ty: Type,
+    is_min: bool,
+) -> &'a Value {
+    let predicate = if is_min {
+        LLVMRealPredicate::LLVMRealOLT
+    } else {
+        LLVMRealPredicate::LLVMRealOGT
+    };
+    let picked = b.build_select(build_fcmp(b, v1, v2, predicate), v1, v2);
+
+    // Equal operands only differ if they are zeros of opposite sign, where min wants -0.0 and max +0.0
+    let int_type = if ty == Type::F32 {
+        <i32>::get_type(ctx)
+    } else {
+        <i64>::get_type(ctx)
+    };
+    let (bits1, bits2) = (b.build_bit_cast(v1, int_type), b.build_bit_cast(v2, int_type));
+    let merged_bits = if is_min {
+        b.build_or(bits1, bits2)
+    } else {
+        b.build_and(bits1, bits2)
+    };
+    let merged = b.build_bit_cast(merged_bits, wasm_type_to_llvm_type(ctx, ty));
+    let picked = b.build_select(build_fcmp(b, v1, v2, LLVMRealPredicate::LLVMRealOEQ), merged, picked);
+
+    // Adding a NaN to anything gives a NaN, which is how wasm propagates it
+    let nan = b.build_add(v1, v2);
+    b.build_select(build_fcmp(b, v1, v2, LLVMRealPredicate::LLVMRealUNO), nan, picked)
+}
+
+// Emits a conditional branch to a trap, and leaves the builder positioned in the block where execution continues
+pub fn build_trap_if<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    cond: &'a Value,
+) -> &'a BasicBlock {
+    let trap_bb = f_ctx.generate_block();
+    let continue_bb = f_ctx.generate_block();
+    b.build_cond_br(cond, trap_bb, Some(continue_bb));
+
+    b.position_at_end(trap_bb);
+    b.build_call(get_stub_function(m_ctx, TRAP), &[]);
+    b.build_unreachable();
+
+    b.position_at_end(continue_bb);
+    continue_bb
+}
+
+// The range of floats a truncation accepts, as (lower, lower_inclusive, upper) with the upper bound always exclusive
+// The lower bound is inclusive when the integer minimum is representable in the float type, and exclusive otherwise
+fn trunc_bounds(from: Type, to: Type, signed: bool) -> (f64, bool, f64) {
+    let bits = if to == Type::I32 { 32 } else { 64 };
+    if !signed {
+        (-1.0, false, 2f64.powi(bits))
+    } else if from == Type::F32 || bits == 64 {
+        (-(2f64.powi(bits - 1)), true, 2f64.powi(bits - 1))
+    } else {
+        (-(2f64.powi(bits - 1)) - 1.0, false, 2f64.powi(bits - 1))
+    }
+}
+
+// Wasm truncations trap on NaN and on values outside the integer range, where fptosi/fptoui would produce poison
+// Returns the basic block that the rest of the instructions should be emitted into
+fn float_trunc_to_int<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    stack: &mut Vec<&'a Value>,
+    basic_block: &'a BasicBlock,
+    from: Type,
+    to: Type,
+    signed: bool,
+) -> &'a BasicBlock {
+    let v = stack.pop().unwrap();
+    assert_type(m_ctx, v, from);
+
+    let int_type = wasm_type_to_llvm_type(m_ctx.llvm_ctx, to);
+    let convert = |v| {
+        if signed {
+            b.build_fptosi(v, int_type)
+        } else {
+            b.build_fptoui(v, int_type)
+        }
+    };
+
+    if m_ctx.opt.use_fast_unsafe_implementations {
+        stack.push(convert(v));
+        return basic_block;
+    }
+
+    let (lower, lower_inclusive, upper) = trunc_bounds(from, to, signed);
+    let (lower, upper) = if from == Type::F32 {
+        ((lower as f32).compile(m_ctx.llvm_ctx), (upper as f32).compile(m_ctx.llvm_ctx))
+    } else {
+        (lower.compile(m_ctx.llvm_ctx), upper.compile(m_ctx.llvm_ctx))
+    };
+
+    // Unordered predicates make NaN land on the trapping side
+    let below_predicate = if lower_inclusive {
+        LLVMRealPredicate::LLVMRealULT
+    } else {
+        LLVMRealPredicate::LLVMRealULE
+    };
+    let too_small = build_fcmp(b, v, lower, below_predicate);
+    let too_big = build_fcmp(b, v, upper, LLVMRealPredicate::LLVMRealUGE);
+    let out_of_range = b.build_or(too_small, too_big);
+
+    let continue_bb = build_trap_if(m_ctx, f_ctx, b, out_of_range);
+    stack.push(convert(v));
+    continue_bb
+}
+
+// With --vmctx linear memory belongs to the instance, otherwise it is the module's global
+fn linear_memory_address<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    total_offset: &'a Value,
+) -> &'a Value {
+    match f_ctx.vmctx {
+        Some(_) => {
+            let memory_base = b.build_load(build_instance_field_ptr(m_ctx, f_ctx, b, MEMORY_BASE_FIELD));
+            b.build_gep(memory_base, &[total_offset])
+        }
+        // A memory image is a struct (or a string) rather than an array, so it is indexed by bytes
+        None if m_ctx.opt.memory_image => {
+            let memory_base = b.build_bit_cast(
+                m_ctx.linear_memory.unwrap().to_super(),
+                PointerType::new(<u8>::get_type(m_ctx.llvm_ctx)),
+            );
+            b.build_gep(memory_base, &[total_offset])
+        }
+        None => b.build_gep(m_ctx.linear_memory.unwrap(), &[total_offset]),
+    }
+}
+
 fn load_val<'a, L: Compile<'a>>(
     m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
     b: &'a Builder,
     stack: &mut Vec<&'a Value>,
     offset: u32,
@@ -1260,8 +1526,7 @@ fn load_val<'a, L: Compile<'a>>(
 
     let ty = L::get_type(m_ctx.llvm_ctx);
 
-    let ptr = b.build_gep(m_ctx.linear_memory.unwrap()
-    , &[total_offset]);
+    let ptr = linear_memory_address(m_ctx, f_ctx, b, total_offset);
 
     let data_raw_ptr =
     b.build_bit_cast(ptr, 
@@ -1291,11 +1556,12 @@ fn load_val<'a, L: Compile<'a>>(
 
 fn load_as_i32_sext<'a, L: Compile<'a>>(
     m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
     b: &'a Builder,
     stack: &mut Vec<&'a Value>,
     offset: u32,
 ) {
-    let val = load_val::<L>(m_ctx, b, stack, offset);
+    let val = load_val::<L>(m_ctx, f_ctx, b, stack, offset);
     let val_as_i32 = b.build_sext(val, <i32>::get_type(m_ctx.llvm_ctx));
 
     stack.push(val_as_i32);
@@ -1303,11 +1569,12 @@ fn load_as_i32_sext<'a, L: Compile<'a>>(
 
 fn load_as_i32_zext<'a, L: Compile<'a>>(
     m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
     b: &'a Builder,
     stack: &mut Vec<&'a Value>,
     offset: u32,
 ) {
-    let val = load_val::<L>(m_ctx, b, stack, offset);
+    let val = load_val::<L>(m_ctx, f_ctx, b, stack, offset);
     let val_as_i32 = b.build_zext(val, <i32>::get_type(m_ctx.llvm_ctx));
 
     stack.push(val_as_i32);
@@ -1315,28 +1582,31 @@ fn load_as_i32_zext<'a, L: Compile<'a>>(
 
 fn load_as_i64_sext<'a, L: Compile<'a>>(
     m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
     b: &'a Builder,
     stack: &mut Vec<&'a Value>,
     offset: u32,
 ) {
-    let val = load_val::<L>(m_ctx, b, stack, offset);
+    let val = load_val::<L>(m_ctx, f_ctx, b, stack, offset);
     let val_as_i64 = b.build_sext(val, <i64>::get_type(m_ctx.llvm_ctx));
     stack.push(val_as_i64);
 }
 
 fn load_as_i64_zext<'a, L: Compile<'a>>(
     m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
     b: &'a Builder,
     stack: &mut Vec<&'a Value>,
     offset: u32,
 ) {
-    let val = load_val::<L>(m_ctx, b, stack, offset);
+    let val = load_val::<L>(m_ctx, f_ctx, b, stack, offset);
     let val_as_i64 = b.build_zext(val, <i64>::get_type(m_ctx.llvm_ctx));
     stack.push(val_as_i64);
 }
 
 fn store_val<'a, L: Compile<'a>>(
     m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
     b: &'a Builder,
     stack: &mut Vec<&'a Value>,
     offset: u32,
@@ -1349,8 +1619,7 @@ fn store_val<'a, L: Compile<'a>>(
 
     let ty = L::get_type(m_ctx.llvm_ctx);
 
-    let ptr = b.build_gep(m_ctx.linear_memory.unwrap()
-    , &[total_offset]);
+    let ptr = linear_memory_address(m_ctx, f_ctx, b, total_offset);
 
     let data_raw_ptr =
     b.build_bit_cast(ptr, 
@@ -1376,3 +1645,127 @@ fn store_val<'a, L: Compile<'a>>(
 
     b.build_call(f, &[total_offset, val]);*/
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The builder folds constant operands, so the emitted sequence evaluates without running anything
+    fn min_max(v1: f64, v2: f64, ty: Type, is_min: bool) -> f64 {
+        let ctx = LLVMCtx::new();
+        let b = Builder::new(&ctx);
+        let (v1, v2) = if ty == Type::F32 {
+            ((v1 as f32).compile(&ctx), (v2 as f32).compile(&ctx))
+        } else {
+            (v1.compile(&ctx), v2.compile(&ctx))
+        };
+        let result = build_nan_propagating_min_max(&ctx, &b, v1, v2, ty, is_min);
+        let mut loses_info = 0;
+        unsafe { llvm::ffi::core::LLVMConstRealGetDouble(result.into(), &mut loses_info) }
+    }
+
+    #[test]
+    fn min_max_propagate_nan() {
+        for &ty in &[Type::F32, Type::F64] {
+            for &is_min in &[true, false] {
+                assert!(min_max(std::f64::NAN, 1.0, ty, is_min).is_nan());
+                assert!(min_max(1.0, std::f64::NAN, ty, is_min).is_nan());
+                assert!(min_max(std::f64::NAN, std::f64::NAN, ty, is_min).is_nan());
+                assert!(min_max(std::f64::NAN, std::f64::INFINITY, ty, is_min).is_nan());
+            }
+        }
+    }
+
+    #[test]
+    fn min_max_order_zeros_by_sign() {
+        for &ty in &[Type::F32, Type::F64] {
+            assert!(min_max(0.0, -0.0, ty, true).is_sign_negative());
+            assert!(min_max(-0.0, 0.0, ty, true).is_sign_negative());
+            assert!(min_max(0.0, -0.0, ty, false).is_sign_positive());
+            assert!(min_max(-0.0, 0.0, ty, false).is_sign_positive());
+            assert!(min_max(-0.0, -0.0, ty, false).is_sign_negative());
+        }
+    }
+
+    #[test]
+    fn min_max_pick_the_right_operand() {
+        for &ty in &[Type::F32, Type::F64] {
+            assert_eq!(min_max(1.5, -2.0, ty, true), -2.0);
+            assert_eq!(min_max(1.5, -2.0, ty, false), 1.5);
+            assert_eq!(min_max(3.0, 3.0, ty, true), 3.0);
+            assert_eq!(min_max(std::f64::NEG_INFINITY, 7.0, ty, true), std::f64::NEG_INFINITY);
+            assert_eq!(min_max(std::f64::NEG_INFINITY, 7.0, ty, false), 7.0);
+        }
+    }
+
+    // Whether a truncation with these bounds accepts v, mirroring the compares float_trunc_to_int emits
+    fn accepts(v: f64, from: Type, to: Type, signed: bool) -> bool {
+        let (lower, lower_inclusive, upper) = trunc_bounds(from, to, signed);
+        let (lower, upper) = if from == Type::F32 {
+            (lower as f32 as f64, upper as f32 as f64)
+        } else {
+            (lower, upper)
+        };
+        let above_lower = if lower_inclusive { v >= lower } else { v > lower };
+        above_lower && v < upper
+    }
+
+    fn fits(v: f64, to: Type, signed: bool) -> bool {
+        let t = v.trunc() as i128;
+        match (to, signed) {
+            (Type::I32, true) => i32::min_value() as i128 <= t && t <= i32::max_value() as i128,
+            (Type::I32, false) => 0 <= t && t <= u32::max_value() as i128,
+            (_, true) => i64::min_value() as i128 <= t && t <= i64::max_value() as i128,
+            (_, false) => 0 <= t && t <= u64::max_value() as i128,
+        }
+    }
+
+    #[test]
+    fn trunc_traps_exactly_outside_the_integer_range() {
+        for &from in &[Type::F32, Type::F64] {
+            for &to in &[Type::I32, Type::I64] {
+                for &signed in &[true, false] {
+                    let (lower, _, upper) = trunc_bounds(from, to, signed);
+                    let mut samples = vec![0.0, -0.0, 0.5, -0.5, -0.99, 1.0, -1.0, 123.75];
+                    for &bound in &[lower, upper, 2f64.powi(31), -(2f64.powi(31)), 2f64.powi(63), -(2f64.powi(63))] {
+                        // The bound itself, and the neighbouring values representable in the source type
+                        if from == Type::F32 {
+                            let bound = bound as f32;
+                            samples.push(bound as f64);
+                            samples.push(f32::from_bits(bound.to_bits() + 1) as f64);
+                            samples.push(f32::from_bits(bound.to_bits() - 1) as f64);
+                        } else {
+                            samples.push(bound);
+                            samples.push(f64::from_bits(bound.to_bits() + 1));
+                            samples.push(f64::from_bits(bound.to_bits() - 1));
+                        }
+                    }
+                    for v in samples {
+                        assert_eq!(
+                            accepts(v, from, to, signed),
+                            fits(v, to, signed),
+                            "{:?} -> {:?} (signed {}) of {}",
+                            from,
+                            to,
+                            signed,
+                            v
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trunc_traps_on_nan_and_infinities() {
+        for &from in &[Type::F32, Type::F64] {
+            for &to in &[Type::I32, Type::I64] {
+                for &signed in &[true, false] {
+                    assert!(!accepts(std::f64::NAN, from, to, signed));
+                    assert!(!accepts(std::f64::INFINITY, from, to, signed));
+                    assert!(!accepts(std::f64::NEG_INFINITY, from, to, signed));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use llvm::BasicBlock;
+use llvm::Builder;
+use llvm::Compile;
+use llvm::Value;
+
+use crate::codegen::function::FunctionCtx;
+use crate::codegen::memory::evaluate_constant_offset;
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::type_conversions::wasm_type_to_zeroed_value;
+use crate::codegen::ModuleCtx;
+use crate::wasm::WasmModule;
+
+// Past this many possible targets a chain of direct calls stops beating a table lookup
+const MAX_GUARDED_TARGETS: usize = 4;
+
+// Works out what every table slot holds for the whole life of the module, if that can be known at compile time
+// Wasm code cannot write to the table, and we never import or export one, so only the element segments matter
+pub fn analyze_static_table(wasm_module: &WasmModule) -> Option<Vec<Option<u32>>> {
+    if wasm_module.tables.len() != 1 {
+        return None;
+    }
+
+    let mut slots = vec![None; wasm_module.tables[0].limits.initial as usize];
+    for i in &wasm_module.table_initializers {
+        let offset = evaluate_constant_offset(&i.offset_expression)? as usize;
+        for (n, &f_index) in i.function_indexes.iter().enumerate() {
+            *slots.get_mut(offset + n)? = Some(f_index);
+        }
+    }
+    Some(slots)
+}
+
+fn constant_table_index(v: &Value) -> Option<u32> {
+    unsafe {
+        use llvm::ffi::core;
+        if core::LLVMIsAConstantInt(v.into()).is_null() {
+            None
+        } else {
+            Some(core::LLVMConstIntGetZExtValue(v.into()) as u32)
+        }
+    }
+}
+
+// Replaces an indirect call with direct calls, if the table is static and the possible targets are few enough
+// Returns the call result and the basic block to continue in, or None if the call has to stay indirect
+pub fn build_devirtualized_call<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    basic_block: &'a BasicBlock,
+    table_index: &'a Value,
+    type_index: u32,
+    args: &[&'a Value],
+) -> Option<(Option<&'a Value>, &'a BasicBlock)> {
+    if m_ctx.opt.no_devirtualize {
+        return None;
+    }
+    let static_table = m_ctx.static_table?;
+
+    let type_id = m_ctx.canonical_type_id(type_index);
+    let type_matches = |slot: usize| match static_table.get(slot) {
+        Some(&Some(f_index)) => {
+            m_ctx.canonical_type_id(m_ctx.functions[f_index as usize].1.get_type_index()) == type_id
+        }
+        _ => false,
+    };
+
+    let constant_index = constant_table_index(table_index);
+    let candidates: Vec<usize> = match constant_index {
+        Some(slot) => Some(slot as usize).filter(|&s| type_matches(s)).into_iter().collect(),
+        None => (0..static_table.len()).filter(|&s| type_matches(s)).collect(),
+    };
+    if constant_index.is_none() && candidates.len() > MAX_GUARDED_TARGETS {
+        return None;
+    }
+
+    let f_type = &m_ctx.types[type_index as usize];
+    let return_type = f_type.returns.first().cloned();
+    let direct_call = |slot: usize| {
+        let f_index = static_table[slot].unwrap();
+        let (llvm_f, _) = m_ctx.functions[f_index as usize];
+        b.build_call(llvm_f, &f_ctx.call_args(args))
+    };
+
+    // A known index into a known slot is just a direct call
+    if constant_index.is_some() && candidates.len() == 1 {
+        let result = direct_call(candidates[0]);
+        return Some((return_type.map(|_| result), basic_block));
+    }
+
+    // Otherwise we switch over the slots that could pass the type check, any other index would trap anyway
+    let trap_bb = f_ctx.generate_block();
+    let join_bb = f_ctx.generate_block();
+
+    let mut switch_options: Vec<(&Value, &BasicBlock)> = Vec::new();
+    let mut results: Vec<(&BasicBlock, &Value)> = Vec::new();
+    for &slot in &candidates {
+        let call_bb = f_ctx.generate_block();
+        switch_options.push(((slot as u32).compile(m_ctx.llvm_ctx), call_bb));
+    }
+    b.build_switch(table_index, trap_bb, &switch_options);
+
+    for (&slot, &(_, call_bb)) in candidates.iter().zip(switch_options.iter()) {
+        b.position_at_end(call_bb);
+        results.push((call_bb, direct_call(slot)));
+        b.build_br(join_bb);
+    }
+
+    b.position_at_end(trap_bb);
+    b.build_call(get_stub_function(m_ctx, TRAP), &[]);
+    b.build_unreachable();
+
+    b.position_at_end(join_bb);
+    let result = return_type.map(|ty| {
+        if results.is_empty() {
+            // Nothing can reach here, but the stack still needs a value
+            wasm_type_to_zeroed_value(m_ctx.llvm_ctx, ty)
+        } else {
+            b.build_phi(results)
+        }
+    });
+    Some((result, join_bb))
+}
@@ -0,0 +1,157 @@
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::io;
+use std::os::raw::c_char;
+use std::ptr;
+use std::str::FromStr;
+
+use llvm::ffi::prelude::LLVMModuleRef;
+use llvm::ffi::target_machine::*;
+use llvm::Module as LLVMModule;
+
+use crate::codegen::TargetSettings;
+use crate::Opt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmitKind {
+    Bitcode,
+    Ir,
+    Assembly,
+    Object,
+}
+
+impl EmitKind {
+    pub fn default_extension(self) -> &'static str {
+        match self {
+            EmitKind::Bitcode => "bc",
+            EmitKind::Ir => "ll",
+            EmitKind::Assembly => "s",
+            EmitKind::Object => "o",
+        }
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bc" => Ok(EmitKind::Bitcode),
+            "ll" => Ok(EmitKind::Ir),
+            "asm" => Ok(EmitKind::Assembly),
+            "obj" => Ok(EmitKind::Object),
+            e => Err(format!("unknown emit kind {}, expected one of bc, ll, asm, obj", e)),
+        }
+    }
+}
+
+fn llvm_error(message: *mut c_char) -> io::Error {
+    let text = unsafe {
+        let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+        llvm::ffi::core::LLVMDisposeMessage(message);
+        text
+    };
+    io::Error::new(io::ErrorKind::Other, text)
+}
+
+// Builds a target machine for the resolved target, or the host if no target was given
+// The machine owns no reference to the module, so the caller must dispose of it
+pub fn create_target_machine(opt: &Opt, target_settings: &TargetSettings) -> io::Result<LLVMTargetMachineRef> {
+    unsafe {
+        llvm::ffi::target::LLVM_InitializeAllTargetInfos();
+        llvm::ffi::target::LLVM_InitializeAllTargets();
+        llvm::ffi::target::LLVM_InitializeAllTargetMCs();
+        llvm::ffi::target::LLVM_InitializeAllAsmPrinters();
+
+        let triple = match target_settings.triple {
+            Some(ref target) => CString::new(target.as_str()).unwrap(),
+            None => {
+                let default = LLVMGetDefaultTargetTriple();
+                let owned = CStr::from_ptr(default).to_owned();
+                llvm::ffi::core::LLVMDisposeMessage(default);
+                owned
+            }
+        };
+
+        let mut target = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        if LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut error) != 0 {
+            return Err(llvm_error(error));
+        }
+
+        let cpu = CString::new(target_settings.cpu.clone().unwrap_or_default()).unwrap();
+        let features = CString::new(target_settings.features.clone().unwrap_or_default()).unwrap();
+        Ok(LLVMCreateTargetMachine(
+            target,
+            triple.as_ptr(),
+            cpu.as_ptr(),
+            features.as_ptr(),
+            opt.opt_level
+                .map(|level| level.codegen_level())
+                .unwrap_or(LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault),
+            // The target's own default, so bare metal targets get static code and hosted ones what their linker expects
+            LLVMRelocMode::LLVMRelocDefault,
+            LLVMCodeModel::LLVMCodeModelDefault,
+        ))
+    }
+}
+
+// The data layout LLVM's own backend uses for the target, so the IR is folded and optimized for the target it is emitted for
+pub fn target_data_layout(opt: &Opt, target_settings: &TargetSettings) -> io::Result<String> {
+    unsafe {
+        let target_machine = create_target_machine(opt, target_settings)?;
+        let data_layout = LLVMCreateTargetDataLayout(target_machine);
+        let layout = llvm::ffi::target::LLVMCopyStringRepOfTargetData(data_layout);
+        let owned = CStr::from_ptr(layout).to_string_lossy().into_owned();
+        llvm::ffi::core::LLVMDisposeMessage(layout);
+        llvm::ffi::target::LLVMDisposeTargetData(data_layout);
+        LLVMDisposeTargetMachine(target_machine);
+        Ok(owned)
+    }
+}
+
+// Writes the finished module out in whatever form `--emit` asked for
+pub fn emit_module(
+    opt: &Opt,
+    target_settings: &TargetSettings,
+    llvm_module: &LLVMModule,
+    output_path: &str,
+) -> io::Result<()> {
+    let module_ref: LLVMModuleRef = llvm_module.into();
+    let c_output_path = CString::new(output_path).unwrap();
+
+    match opt.emit {
+        EmitKind::Bitcode => llvm_module.write_bitcode(output_path),
+        EmitKind::Ir => unsafe {
+            let mut error = ptr::null_mut();
+            if llvm::ffi::core::LLVMPrintModuleToFile(module_ref, c_output_path.as_ptr(), &mut error) != 0 {
+                return Err(llvm_error(error));
+            }
+            Ok(())
+        },
+        EmitKind::Assembly | EmitKind::Object => unsafe {
+            let target_machine = create_target_machine(opt, target_settings)?;
+
+            let file_type = if opt.emit == EmitKind::Object {
+                LLVMCodeGenFileType::LLVMObjectFile
+            } else {
+                LLVMCodeGenFileType::LLVMAssemblyFile
+            };
+
+            let mut error = ptr::null_mut();
+            let failed = LLVMTargetMachineEmitToFile(
+                target_machine,
+                module_ref,
+                c_output_path.as_ptr() as *mut c_char,
+                file_type,
+                &mut error,
+            );
+            LLVMDisposeTargetMachine(target_machine);
+
+            if failed != 0 {
+                return Err(llvm_error(error));
+            }
+            Ok(())
+        },
+    }
+}
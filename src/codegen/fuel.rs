@@ -0,0 +1,121 @@
+use llvm::BasicBlock;
+use llvm::Builder;
+use llvm::Compile;
+use llvm::Context as LLVMCtx;
+use llvm::FunctionType;
+use llvm::GlobalVariable;
+use llvm::Module as LLVMModule;
+use llvm::Predicate;
+use llvm::Sub;
+
+use crate::codegen::function::FunctionCtx;
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::thread_local::mark_thread_local;
+use crate::codegen::ModuleCtx;
+use crate::wasm::Instruction;
+use crate::Opt;
+
+// The remaining fuel, once it drops below zero the out of fuel hook is called
+pub const FUEL_GLOBAL: &str = "wasm_fuel";
+pub const FUEL_GET: &str = "wasm_fuel_get";
+pub const FUEL_SET: &str = "wasm_fuel_set";
+// What the counter starts at, so runtime_main and hosts can refill it to the same budget
+pub const INITIAL_FUEL: &str = "wasm_initial_fuel";
+
+pub fn insert_fuel_global<'a>(
+    opt: &Opt,
+    llvm_ctx: &'a LLVMCtx,
+    llvm_module: &'a LLVMModule,
+) -> Option<&'a GlobalVariable> {
+    if !opt.fuel {
+        return None;
+    }
+
+    let initial_fuel = opt.initial_fuel.unwrap_or(i64::max_value());
+    let initial_fuel_global = llvm_module.add_global_variable(INITIAL_FUEL, initial_fuel.compile(llvm_ctx));
+    initial_fuel_global.set_constant(true);
+
+    let fuel = llvm_module.add_global_variable(FUEL_GLOBAL, initial_fuel.compile(llvm_ctx));
+    mark_thread_local(opt, fuel);
+
+    // The host can also poke the global directly, but accessors are friendlier across languages
+    let getter = llvm_module.add_function(
+        FUEL_GET,
+        FunctionType::new(<i64>::get_type(llvm_ctx), &[]).to_super(),
+    );
+    let b = Builder::new(llvm_ctx);
+    b.position_at_end(getter.append("entry"));
+    b.build_ret(b.build_load(fuel));
+
+    let setter = llvm_module.add_function(
+        FUEL_SET,
+        FunctionType::new(<()>::get_type(llvm_ctx), &[<i64>::get_type(llvm_ctx)]).to_super(),
+    );
+    b.position_at_end(setter.append("entry"));
+    b.build_store(&*setter[0], fuel);
+    b.build_ret_void();
+
+    Some(fuel)
+}
+
+// The static cost of the straight line code starting at `instructions`, including the instruction that ends it
+fn straight_line_cost(instructions: &[Instruction]) -> u64 {
+    let mut cost = 0;
+    for inst in instructions {
+        cost += 1;
+        match inst {
+            Instruction::BlockStart { .. }
+            | Instruction::LoopStart { .. }
+            | Instruction::End
+            | Instruction::Br { .. }
+            | Instruction::BrIf { .. }
+            | Instruction::BrTable { .. }
+            | Instruction::Return
+            | Instruction::Unreachable
+            | Instruction::Call { .. }
+            | Instruction::CallIndirect { .. } => break,
+            _ => {}
+        }
+    }
+    cost
+}
+
+// Charges for the code about to be compiled, and calls the out of fuel hook if that overdraws the counter
+// Returns the basic block the metered code should be emitted into
+pub fn build_fuel_check<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    basic_block: &'a BasicBlock,
+    upcoming: &[Instruction],
+) -> &'a BasicBlock {
+    let fuel = match m_ctx.fuel {
+        Some(fuel) => fuel,
+        None => return basic_block,
+    };
+
+    let cost = straight_line_cost(upcoming) as i64;
+    if cost == 0 {
+        return basic_block;
+    }
+
+    let remaining = b.build_sub(b.build_load(fuel), cost.compile(m_ctx.llvm_ctx));
+    b.build_store(remaining, fuel);
+    let exhausted = b.build_signed_cmp(
+        remaining,
+        0i64.compile(m_ctx.llvm_ctx),
+        Predicate::LessThan,
+    );
+
+    // The hook may refill the counter and return, or never return at all
+    let exhausted_bb = f_ctx.generate_block();
+    let continue_bb = f_ctx.generate_block();
+    b.build_cond_br(exhausted, exhausted_bb, Some(continue_bb));
+
+    b.position_at_end(exhausted_bb);
+    b.build_call(get_stub_function(m_ctx, OUT_OF_FUEL), &[]);
+    b.build_br(continue_bb);
+
+    b.position_at_end(continue_bb);
+    continue_bb
+}
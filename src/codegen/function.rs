@@ -1,7 +1,6 @@
 use std::cell::Cell;
 use std::ffi::CString;
 use std::mem;
-use std::sync::atomic::{AtomicBool, Ordering};
 
 use llvm::{BasicBlock, Sub};
 use llvm::Builder;
@@ -12,6 +11,9 @@ use wasmparser::TypeOrFuncType;
 
 use crate::codegen::block::compile_block;
 use crate::codegen::breakout::BreakoutTarget;
+use crate::codegen::interrupt::build_interrupt_check;
+use crate::codegen::stack_guard::build_stack_guard_epilogue;
+use crate::codegen::stack_guard::build_stack_guard_prologue;
 use crate::codegen::type_conversions::wasm_type_to_zeroed_value;
 use crate::codegen::ModuleCtx;
 
@@ -21,10 +23,16 @@ pub struct FunctionCtx<'a> {
     pub llvm_f: &'a Function,
     pub builder: &'a Builder,
     pub has_return: bool,
+    pub vmctx: Option<&'a Value>,
     block_counter: Cell<u32>,
 }
 
 impl<'a> FunctionCtx<'a> {
+    // Calls between wasm functions pass the caller's instance along
+    pub fn call_args(&self, args: &[&'a Value]) -> Vec<&'a Value> {
+        self.vmctx.iter().cloned().chain(args.iter().cloned()).collect()
+    }
+
     pub fn generate_block(&self) -> &BasicBlock {
         let n = self.block_counter.get();
         let result = self.llvm_f.append(&format!("b_{}", n));
@@ -56,37 +64,9 @@ unsafe fn add_string_attr(k: &str, v: &str, v_ref: *mut llvm::ffi::LLVMValue, ct
 
 }
 
-// HACK: We only want to emit a note about the cortex-m override once
-// TODO: Is this clearer than doing it up a level in the code?
-static CORTEX_OVERRIDE_MESSAGE_SENT: AtomicBool = AtomicBool::new(false);
-
 pub fn compile_function(ctx: &ModuleCtx, f: &ImplementedFunction) {
     let llvm_f = ctx.llvm_module.get_function(&f.generated_name).unwrap();
 
-    // FIXME: This is a performance hack to include target features for cortex-m
-    // The version of clang we're using for the cortex-m work needs these (at least for lto),
-    // otherwise it falls back to slow defaults
-    //
-    // attributes #0 = {
-    // nounwind
-    // "correctly-rounded-divide-sqrt-fp-math"="false"
-    // "disable-tail-calls"="false"
-    // "less-precise-fpmad"="false"
-    // "min-legal-vector-width"="0"
-    // "no-frame-pointer-elim"="true"
-    // "no-frame-pointer-elim-non-leaf"
-    // "no-infs-fp-math"="false"
-    // "no-jump-tables"="false"
-    // "no-nans-fp-math"="false"
-    // "no-signed-zeros-fp-math"="false"
-    // "no-trapping-math"="false"
-    // "stack-protector-buffer-size"="8"
-    // "target-cpu"="cortex-m7"
-    // "target-features"="+armv7e-m,+dsp,+fp-armv8d16,+fp-armv8d16sp,+fp16,+fp64,+fpregs,+hwdiv,+thumb-mode,+vfp2,+vfp2d16,+vfp2d16sp,+vfp2sp,+vfp3d16,+vfp3d16sp,+vfp4d16,+vfp4d16sp,-aes,-crc,-crypto,-dotprod,-fp16fml,-fullfp16,-hwdiv-arm,-lob,-mve,-mve.fp,-ras,-sb,-sha2"
-    // "unsafe-fp-math"="false"
-    // "use-soft-float"="false" }
-    let cm_override = false;
-
     unsafe {
         let v_ref: *mut llvm::ffi::LLVMValue = mem::transmute(llvm_f.to_super() as &Value);
         let llvm_ctx: *mut llvm::ffi::LLVMContext = mem::transmute(ctx.llvm_ctx as &llvm::Context);
@@ -101,125 +81,37 @@ pub fn compile_function(ctx: &ModuleCtx, f: &ImplementedFunction) {
             attr_ref
         );
 
-        if cm_override {
-            if !CORTEX_OVERRIDE_MESSAGE_SENT.load(Ordering::Relaxed) {
-                info!("engaging cortex-m override");
-
-                // Only send the message once!
-                CORTEX_OVERRIDE_MESSAGE_SENT.store(true, Ordering::Relaxed);
-            }
-
-            add_string_attr(
-                "correctly-rounded-divide-sqrt-fp-math",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "disable-tail-calls",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "less-precise-fpmad",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "min-legal-vector-width",
-                "0",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "no-frame-pointer-elim",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "no-infs-fp-math",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "no-jump-tables",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "no-nans-fp-math",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "no-signed-zeros-fp-math",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "no-trapping-math",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            // add_string_attr(
-            //     "stack-protector-buffer-size",
-            //     "8",
-            //     v_ref,
-            //     ctx
-            // );
-
-            add_string_attr(
-                "target-cpu",
-                "cortex-m7",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "unsafe-fp-math",
-                "false",
-                v_ref,
-                ctx
-            );
-
-            add_string_attr(
-                "use-soft-float",
-                "false",
-                v_ref,
-                ctx
-            );
-
+        if ctx.opt.deterministic {
+            // Without these LLVM is free to change rounding per target
+            // Multiplies and adds are never fused regardless: we never emit llvm.fmuladd or `contract` flags,
+            // and the target machine keeps the default FP op fusion mode, which only fuses those
+            add_string_attr("unsafe-fp-math", "false", v_ref, ctx);
+            add_string_attr("less-precise-fpmad", "false", v_ref, ctx);
+            add_string_attr("no-nans-fp-math", "false", v_ref, ctx);
+            add_string_attr("no-infs-fp-math", "false", v_ref, ctx);
+            add_string_attr("no-signed-zeros-fp-math", "false", v_ref, ctx);
+        }
 
-            add_string_attr(
-                "target-features",
-                "+armv7e-m,+dsp,+fp-armv8d16,+fp-armv8d16sp,+fp16,+fp64,+fpregs,+hwdiv,+thumb-mode,+vfp2,+vfp2d16,+vfp2d16sp,+vfp2sp,+vfp3d16,+vfp3d16sp,+vfp4d16,+vfp4d16sp,-aes,-crc,-crypto,-dotprod,-fp16fml,-fullfp16,-hwdiv-arm,-lob,-mve,-mve.fp,-ras,-sb,-sha2",
-                v_ref,
-                ctx
-            );
+        // Some backends (the cortex-m lto toolchain in particular) ignore the target machine settings,
+        // and fall back to slow defaults unless every function carries them
+        if let Some(ref cpu) = ctx.target.cpu {
+            add_string_attr("target-cpu", cpu, v_ref, ctx);
+        }
+        if let Some(ref features) = ctx.target.features {
+            add_string_attr("target-features", features, v_ref, ctx);
+        }
+        for &(k, v) in &ctx.target.function_attributes {
+            add_string_attr(k, v, v_ref, ctx);
         }
     }
 
+    // With --vmctx the instance pointer comes first, and is not visible to wasm code
+    let vmctx: Option<&Value> = ctx.instance_type.map(|_| &*llvm_f[0]);
+    let first_param = if vmctx.is_some() { 1 } else { 0 };
+
     let mut locals: Vec<&Value> = Vec::new();
     // In WASM, locals start with function arguments
-    for i in 0..llvm_f.get_signature().get_params().len() {
+    for i in first_param..llvm_f.get_signature().get_params().len() {
         locals.push(&*llvm_f[i]);
     }
     // Then the actual locals
@@ -235,9 +127,14 @@ pub fn compile_function(ctx: &ModuleCtx, f: &ImplementedFunction) {
         llvm_f,
         builder,
         has_return: f.has_return(),
+        vmctx,
         block_counter: Cell::new(0),
     };
 
+    // The stack guard has to run before anything else in the function, and may split the entry block
+    let body_bb = build_stack_guard_prologue(ctx, &f_ctx, initial_bb);
+    let body_bb = build_interrupt_check(ctx, &f_ctx, builder, body_bb);
+
     let termination_block = llvm_f.append("exit");
     let root_breakout_target = BreakoutTarget::new_wrapped(termination_block, f.get_return_type().map(TypeOrFuncType::Type));
 
@@ -253,7 +150,7 @@ pub fn compile_function(ctx: &ModuleCtx, f: &ImplementedFunction) {
         &mut breakout_stack,
         &root_breakout_target.clone(),
         locals,
-        initial_bb,
+        body_bb,
         f.code.as_slice(),
     );
 
@@ -262,6 +159,7 @@ pub fn compile_function(ctx: &ModuleCtx, f: &ImplementedFunction) {
         .borrow()
         .build_result(ctx.llvm_ctx, builder);
     builder.position_at_end(termination_block);
+    build_stack_guard_epilogue(ctx, builder);
     match result {
         Some(v) => builder.build_ret(v),
         None => builder.build_ret_void(),
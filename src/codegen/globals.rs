@@ -1,3 +1,5 @@
+use std::io;
+
 use llvm::Builder;
 use llvm::Compile;
 use llvm::Context as LLVMCtx;
@@ -8,27 +10,44 @@ use llvm::Value;
 
 use wasmparser::Type;
 
+use crate::wasm::Export;
 use crate::wasm::Global;
 use crate::wasm::Instruction;
 use crate::Opt;
 
 use crate::codegen::ModuleCtx;
+use crate::codegen::function::FunctionCtx;
+use crate::codegen::thread_local::mark_thread_local;
+use crate::codegen::vmctx::build_instance_field_ptr;
+use crate::codegen::vmctx::FIRST_GLOBAL_FIELD;
 use crate::codegen::runtime_stubs::*;
 use crate::codegen::type_conversions::llvm_type_to_wasm_type;
 use crate::codegen::type_conversions::wasm_type_to_llvm_type;
 
+// Generated with --runtime-globals, the runtime calls it to give the module's globals their initial values
+pub const POPULATE_GLOBALS: &str = "populate_globals";
+// Also with --runtime-globals, which runtime global holds __heap_base, where the runtime starts allocating
+pub const HEAP_BASE_INDEX: &str = "wasm_heap_base_index";
+// The size of the runtime's runtime_globals array, RUNTIME_GLOBALS_MAX in runtime.h
+const RUNTIME_GLOBALS_MAX: usize = 1024;
+
 pub enum GlobalValue<'a> {
     InlinedConstant(&'a Value),
     Native(&'a Value),
     RuntimeI32(u32),
     RuntimeI64(u32),
+    RuntimeF32(u32),
+    RuntimeF64(u32),
+    // A field of the instance struct, the value is what wasm_instance_init stores there
+    Instance { field: u32, initial: &'a Value },
 }
 
 impl<'a> GlobalValue<'a> {
-    pub fn load(&self, m_ctx: &'a ModuleCtx, b: &'a Builder) -> &'a Value {
+    pub fn load(&self, m_ctx: &'a ModuleCtx, f_ctx: &'a FunctionCtx, b: &'a Builder) -> &'a Value {
         match self {
             GlobalValue::InlinedConstant(v) => v,
             GlobalValue::Native(ptr) => b.build_load(ptr),
+            GlobalValue::Instance { field, .. } => b.build_load(build_instance_field_ptr(m_ctx, f_ctx, b, *field)),
             GlobalValue::RuntimeI32(idx) => {
                 let func = get_stub_function(m_ctx, GET_GLOBAL_I32);
                 b.build_call(func, &[idx.compile(m_ctx.llvm_ctx)])
@@ -37,15 +56,26 @@ impl<'a> GlobalValue<'a> {
                 let func = get_stub_function(m_ctx, GET_GLOBAL_I64);
                 b.build_call(func, &[idx.compile(m_ctx.llvm_ctx)])
             }
+            GlobalValue::RuntimeF32(idx) => {
+                let func = get_stub_function(m_ctx, GET_GLOBAL_F32);
+                b.build_call(func, &[idx.compile(m_ctx.llvm_ctx)])
+            }
+            GlobalValue::RuntimeF64(idx) => {
+                let func = get_stub_function(m_ctx, GET_GLOBAL_F64);
+                b.build_call(func, &[idx.compile(m_ctx.llvm_ctx)])
+            }
         }
     }
 
-    pub fn store(&self, m_ctx: &'a ModuleCtx, b: &'a Builder, v: &'a Value) {
+    pub fn store(&self, m_ctx: &'a ModuleCtx, f_ctx: &'a FunctionCtx, b: &'a Builder, v: &'a Value) {
         match self {
             GlobalValue::InlinedConstant(_) => panic!("Cannot write to an inlined constant"),
             GlobalValue::Native(ptr) => {
                 b.build_store(v, ptr);
             }
+            GlobalValue::Instance { field, .. } => {
+                b.build_store(v, build_instance_field_ptr(m_ctx, f_ctx, b, *field));
+            }
             GlobalValue::RuntimeI32(idx) => {
                 let func = get_stub_function(m_ctx, SET_GLOBAL_I32);
                 b.build_call(func, &[idx.compile(m_ctx.llvm_ctx), v]);
@@ -54,6 +84,14 @@ impl<'a> GlobalValue<'a> {
                 let func = get_stub_function(m_ctx, SET_GLOBAL_I64);
                 b.build_call(func, &[idx.compile(m_ctx.llvm_ctx), v]);
             }
+            GlobalValue::RuntimeF32(idx) => {
+                let func = get_stub_function(m_ctx, SET_GLOBAL_F32);
+                b.build_call(func, &[idx.compile(m_ctx.llvm_ctx), v]);
+            }
+            GlobalValue::RuntimeF64(idx) => {
+                let func = get_stub_function(m_ctx, SET_GLOBAL_F64);
+                b.build_call(func, &[idx.compile(m_ctx.llvm_ctx), v]);
+            }
         }
     }
 }
@@ -63,8 +101,135 @@ pub fn insert_globals<'a>(
     llvm_ctx: &'a LLVMCtx,
     llvm_module: &'a LLVMModule,
     globals: Vec<Global>,
+) -> io::Result<Vec<GlobalValue<'a>>> {
+    if opt.use_runtime_global_handling {
+        insert_runtime_globals(opt, llvm_ctx, llvm_module, globals)
+    } else if opt.vmctx {
+        Ok(insert_instance_globals(opt, llvm_ctx, llvm_module, globals))
+    } else {
+        Ok(insert_native_globals(opt, llvm_ctx, llvm_module, globals))
+    }
+}
+
+// Each instance gets its own copy of the module's globals, so they become fields of the instance struct
+// Imported globals belong to the host, and stay native
+fn insert_instance_globals<'a>(
+    opt: &Opt,
+    llvm_ctx: &'a LLVMCtx,
+    llvm_module: &'a LLVMModule,
+    globals: Vec<Global>,
 ) -> Vec<GlobalValue<'a>> {
-    insert_native_globals(opt, llvm_ctx, llvm_module, globals)
+    let mut field = FIRST_GLOBAL_FIELD;
+    let mut global_values = Vec::new();
+    for g in globals {
+        let v = match g {
+            Global::Imported {
+                name,
+                content_type,
+                mutable,
+                ..
+            } => {
+                let llvm_global =
+                    llvm_module.add_global(&name, wasm_type_to_llvm_type(&*llvm_ctx, content_type));
+                llvm_global.set_constant(!mutable);
+                GlobalValue::Native(llvm_global.to_super())
+            }
+            Global::InModule {
+                content_type,
+                mutable,
+                initializer,
+                ..
+            } => {
+                let initial = initializer_to_value(llvm_ctx, content_type, &initializer);
+                if opt.inline_constant_globals && !mutable {
+                    GlobalValue::InlinedConstant(initial)
+                } else {
+                    field += 1;
+                    GlobalValue::Instance { field: field - 1, initial }
+                }
+            }
+        };
+        global_values.push(v);
+    }
+    global_values
+}
+
+// Every global lives in the runtime, reached through its get and set stubs by wasm global index
+// The host sets imported globals itself before running, and can snapshot or inspect any of them
+fn insert_runtime_globals<'a>(
+    opt: &Opt,
+    llvm_ctx: &'a LLVMCtx,
+    llvm_module: &'a LLVMModule,
+    globals: Vec<Global>,
+) -> io::Result<Vec<GlobalValue<'a>>> {
+    if globals.len() > RUNTIME_GLOBALS_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the module has {} globals, but the runtime only has room for {} runtime globals",
+                globals.len(),
+                RUNTIME_GLOBALS_MAX
+            ),
+        ));
+    }
+
+    let populate_globals = llvm_module.add_function(
+        POPULATE_GLOBALS,
+        FunctionType::new(<()>::get_type(llvm_ctx), &[]).to_super(),
+    );
+    let b = Builder::new(llvm_ctx);
+    b.position_at_end(populate_globals.append("entry"));
+
+    let mut global_values = Vec::new();
+    for (idx, g) in globals.into_iter().enumerate() {
+        let idx = idx as u32;
+        let content_type = match g {
+            Global::Imported { content_type, .. } => content_type,
+            Global::InModule {
+                content_type,
+                mutable,
+                initializer,
+                ..
+            } => {
+                let initial = initializer_to_value(llvm_ctx, content_type, &initializer);
+                let setter = match content_type {
+                    Type::I32 => SET_GLOBAL_I32,
+                    Type::I64 => SET_GLOBAL_I64,
+                    Type::F32 => SET_GLOBAL_F32,
+                    Type::F64 => SET_GLOBAL_F64,
+                    e => panic!("global of type {:?} cannot be a runtime global", e),
+                };
+                // Inlined constants still get their slot populated, so the runtime can read them (like __heap_base)
+                b.build_call(llvm_module.get_function(setter).unwrap(), &[idx.compile(llvm_ctx), initial]);
+                if opt.inline_constant_globals && !mutable {
+                    global_values.push(GlobalValue::InlinedConstant(initial));
+                    continue;
+                }
+                content_type
+            }
+        };
+        global_values.push(match content_type {
+            Type::I32 => GlobalValue::RuntimeI32(idx),
+            Type::I64 => GlobalValue::RuntimeI64(idx),
+            Type::F32 => GlobalValue::RuntimeF32(idx),
+            Type::F64 => GlobalValue::RuntimeF64(idx),
+            e => panic!("global of type {:?} cannot be a runtime global", e),
+        });
+    }
+    b.build_ret_void();
+    Ok(global_values)
+}
+
+// The runtime reads wasmg___heap_base directly otherwise, but with --runtime-globals there is no such symbol
+pub fn insert_heap_base_index(llvm_ctx: &LLVMCtx, llvm_module: &LLVMModule, exports: &[Export]) {
+    let heap_base = exports.iter().find_map(|e| match e {
+        Export::Global { name, index } if name == "__heap_base" => Some(*index as i32),
+        _ => None,
+    });
+    if let Some(index) = heap_base {
+        let g = llvm_module.add_global_variable(HEAP_BASE_INDEX, index.compile(llvm_ctx));
+        g.set_constant(true);
+    }
 }
 
 fn insert_native_globals<'a>(
@@ -80,6 +245,7 @@ fn insert_native_globals<'a>(
                 name,
                 content_type,
                 mutable,
+                ..
             } => {
                 let llvm_global =
                     llvm_module.add_global(&name, wasm_type_to_llvm_type(&*llvm_ctx, content_type));
@@ -98,6 +264,7 @@ fn insert_native_globals<'a>(
                 } else {
                     let llvm_global = llvm_module.add_global_variable(&generated_name, v);
                     llvm_global.set_constant(!mutable);
+                    mark_thread_local(opt, llvm_global);
                     GlobalValue::Native(llvm_global.to_super())
                 }
             }
@@ -126,3 +293,32 @@ fn initializer_to_value<'a>(
 
     v
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use structopt::StructOpt;
+
+    use crate::codegen::runtime_stubs::insert_runtime_stubs;
+
+    fn insert_runtime_i32_globals(count: usize) -> bool {
+        let opt = Opt::from_iter(&["silverfish", "--runtime-globals", "test.wasm"]);
+        let ctx = LLVMCtx::new();
+        let module = LLVMModule::new("globals", &ctx);
+        insert_runtime_stubs(&opt, &ctx, &module);
+        let global = Global::InModule {
+            generated_name: "wasmg_g".to_string(),
+            content_type: Type::I32,
+            mutable: true,
+            initializer: vec![Instruction::I32Const(0)],
+        };
+        insert_globals(&opt, &ctx, &module, vec![global; count]).is_ok()
+    }
+
+    #[test]
+    fn runtime_globals_must_fit_the_runtime() {
+        assert!(insert_runtime_i32_globals(RUNTIME_GLOBALS_MAX));
+        assert!(!insert_runtime_i32_globals(RUNTIME_GLOBALS_MAX + 1));
+    }
+}
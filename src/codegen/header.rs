@@ -0,0 +1,182 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::codegen::abi::AbiType;
+use crate::codegen::abi::ModuleAbi;
+
+fn c_type(t: AbiType) -> &'static str {
+    match t {
+        AbiType::I32 => "int32_t",
+        AbiType::I64 => "int64_t",
+        AbiType::F32 => "float",
+        AbiType::F64 => "double",
+        AbiType::U32 => "uint32_t",
+        AbiType::Bytes => "uint8_t*",
+        AbiType::Instance => "struct wasm_instance*",
+    }
+}
+
+fn is_c_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn prototype(symbol: &str, params: &[AbiType], result: Option<AbiType>) -> String {
+    let params = if params.is_empty() {
+        "void".to_string()
+    } else {
+        params.iter().map(|&t| c_type(t)).collect::<Vec<_>>().join(", ")
+    };
+    format!("{} {}({});", result.map(c_type).unwrap_or("void"), symbol, params)
+}
+
+fn push_function(out: &mut String, description: &str, symbol: &str, params: &[AbiType], result: Option<AbiType>) {
+    out.push_str(&format!("/* {} */\n", description));
+    if is_c_identifier(symbol) {
+        out.push_str(&prototype(symbol, params, result));
+    } else {
+        out.push_str(&format!("/* {} is not a valid C identifier, compile with --mangle to use it from C */", symbol));
+    }
+    out.push('\n');
+}
+
+fn include_guard(source_name: &str) -> String {
+    let stem = Path::new(source_name)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem: String = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("SILVERFISH_{}_H", stem)
+}
+
+// Renders the module's ABI as a C header, with the exports to call and the imports the host must define
+fn generate_header(abi: &ModuleAbi) -> String {
+    let guard = include_guard(&abi.source_name);
+    let mut out = String::new();
+    out.push_str(&format!("/* Generated by silverfish from {}, do not edit */\n", abi.source_name));
+    out.push_str(&format!("#ifndef {}\n#define {}\n\n", guard, guard));
+    out.push_str("#include <stdint.h>\n\n");
+    out.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+
+    if abi.vmctx {
+        out.push_str("/* Opaque, allocate wasm_instance_size() bytes for each instance */\n");
+        out.push_str("struct wasm_instance;\n\n");
+    }
+
+    if let Some(ref memory) = abi.memory {
+        out.push_str(&format!("#define WASM_MEMORY_INITIAL_PAGES {}u\n", memory.initial_pages));
+        if let Some(maximum) = memory.maximum_pages {
+            out.push_str(&format!("#define WASM_MEMORY_MAXIMUM_PAGES {}u\n", maximum));
+        }
+    }
+    if let Some(table_size) = abi.table_size {
+        out.push_str(&format!("#define WASM_TABLE_SIZE {}u\n", table_size));
+    }
+    out.push('\n');
+
+    for f in &abi.entry_points {
+        push_function(&mut out, &format!("entry point {}", f.name), &f.symbol, &f.params, f.result);
+    }
+    out.push('\n');
+
+    for f in &abi.exports {
+        push_function(&mut out, &format!("export {}", f.name), &f.symbol, &f.params, f.result);
+    }
+    out.push('\n');
+
+    for f in &abi.imports {
+        let description = format!("import {}.{}, defined by the host", f.module, f.field);
+        push_function(&mut out, &description, &f.symbol, &f.params, f.result);
+    }
+    out.push('\n');
+
+    for g in &abi.globals {
+        out.push_str(&format!("/* export {} */\n", g.name));
+        if let Some(index) = g.runtime_index {
+            out.push_str(&format!("/* runtime global {}, see get_global_* and set_global_* */\n", index));
+            continue;
+        }
+        match g.symbol {
+            Some(ref symbol) if is_c_identifier(symbol) => {
+                let qualifiers = match (g.thread_local, g.mutable) {
+                    (true, true) => "extern _Thread_local ",
+                    (true, false) => "extern _Thread_local const ",
+                    (false, true) => "extern ",
+                    (false, false) => "extern const ",
+                };
+                out.push_str(&format!("{}{} {};\n", qualifiers, c_type(g.value_type), symbol));
+            }
+            Some(ref symbol) => out.push_str(&format!("/* {} is not a valid C identifier */\n", symbol)),
+            None => out.push_str("/* not a symbol, it was inlined or lives in the instance */\n"),
+        }
+    }
+    for g in &abi.imported_globals {
+        out.push_str(&format!("/* import {}.{}, defined by the host */\n", g.module, g.field));
+        if let Some(index) = g.runtime_index {
+            out.push_str(&format!("/* runtime global {}, set it with set_global_* before running */\n", index));
+        } else if is_c_identifier(&g.symbol) {
+            let qualifiers = if g.mutable { "extern " } else { "extern const " };
+            out.push_str(&format!("{}{} {};\n", qualifiers, c_type(g.value_type), g.symbol));
+        } else {
+            out.push_str(&format!("/* {} is not a valid C identifier */\n", g.symbol));
+        }
+    }
+
+    out.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n");
+    out.push_str(&format!("#endif /* {} */\n", guard));
+    out
+}
+
+pub fn write_header(abi: &ModuleAbi, path: &Path) -> io::Result<()> {
+    fs::write(path, generate_header(abi))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use structopt::StructOpt;
+    use wasmparser::Type;
+
+    use crate::codegen::abi::describe_module;
+    use crate::codegen::visibility::Visibility;
+    use crate::wasm::module_with_imports;
+    use crate::wasm::Export;
+    use crate::wasm::Global;
+    use crate::Opt;
+
+    fn header_for_global(args: &[&str], mutable: bool) -> String {
+        let opt = Opt::from_iter(args.iter().chain(&["test.wasm"]));
+        let mut wasm_module = module_with_imports(&[], false);
+        wasm_module.globals.push(Global::InModule {
+            generated_name: "wasmg_counter".to_string(),
+            content_type: Type::I32,
+            mutable,
+            initializer: Vec::new(),
+        });
+        wasm_module.exports.push(Export::Global { name: "counter".to_string(), index: 0 });
+        let visibility = Visibility { public: Vec::new(), live: Vec::new(), public_globals: vec![true] };
+        generate_header(&describe_module(&opt, &wasm_module, &visibility, None))
+    }
+
+    #[test]
+    fn thread_local_mutable_globals_are_declared_thread_local() {
+        let header = header_for_global(&["silverfish", "--thread-local"], true);
+        assert!(header.contains("extern _Thread_local int32_t wasmg_counter;"));
+    }
+
+    #[test]
+    fn thread_local_constant_globals_are_declared_shared() {
+        let header = header_for_global(&["silverfish", "--thread-local"], false);
+        assert!(header.contains("extern const int32_t wasmg_counter;"));
+        assert!(!header.contains("_Thread_local const"));
+    }
+}
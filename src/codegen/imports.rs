@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use llvm::Builder;
+use llvm::Compile;
+use llvm::FunctionType;
+use llvm::PointerType;
+use llvm::Sub;
+use llvm::Value;
+
+use serde::Deserialize;
+
+use wasmparser::FuncType;
+use wasmparser::Type;
+
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::type_conversions::wasm_type_to_llvm_type;
+use crate::codegen::visibility::set_internal;
+use crate::codegen::lowering::generate_lowered_thunk;
+use crate::codegen::lowering::LoweredCall;
+use crate::codegen::vmctx::MEMORY_BASE_FIELD;
+use crate::codegen::ModuleCtx;
+use crate::wasm::Function;
+use crate::wasm::WasmModule;
+
+// What to do with an import the manifest does not mention
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnmappedPolicy {
+    Error,
+    Trap,
+}
+
+impl Default for UnmappedPolicy {
+    fn default() -> Self {
+        UnmappedPolicy::Error
+    }
+}
+
+// One `[imports.<module>.<field>]` table
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportBinding {
+    symbol: String,
+    params: Option<Vec<String>>,
+    results: Option<Vec<String>>,
+    #[serde(default)]
+    pass_memory: bool,
+}
+
+// unmapped = "error" | "trap"
+//
+// [imports.env.__syscall]
+// symbol = "host_syscall"
+// params = ["i32", "i32"]
+// results = ["i32"]
+// pass_memory = true
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportManifest {
+    #[serde(default)]
+    unmapped: UnmappedPolicy,
+    #[serde(default)]
+    imports: HashMap<String, HashMap<String, ImportBinding>>,
+}
+
+pub fn load_import_manifest(path: &Path) -> io::Result<ImportManifest> {
+    let text = fs::read_to_string(path)?;
+    toml::from_str(&text).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a valid import manifest: {}", path.display(), e),
+        )
+    })
+}
+
+// How an imported function is reached once the manifest has been applied
+pub enum ResolvedImport {
+    // Called directly, under the symbol the manifest gave
+    Direct,
+    // Called directly under its default symbol, which the runtime is known to define
+    Runtime,
+    // Called through a generated thunk, which passes the linear memory base before the wasm arguments
+    PassMemory { symbol: String },
+    // Not in the manifest, so calling it traps
+    Trap,
+    // Lowered through a generated thunk onto the runtime's implementation, like WASI calls
+    Lowered { call: &'static LoweredCall },
+    // Does nothing, returning zero if it returns anything
+    Inert,
+}
+
+// The native symbol behind an imported function, or None if calling it just traps
+pub fn native_import_symbol<'a>(appended: &'a str, resolved: Option<&'a ResolvedImport>) -> Option<&'a str> {
+    match resolved {
+        Some(ResolvedImport::PassMemory { symbol }) => Some(symbol),
+        Some(ResolvedImport::Trap) | Some(ResolvedImport::Inert) => None,
+        Some(ResolvedImport::Lowered { call }) => Some(call.symbol),
+        _ => Some(appended),
+    }
+}
+
+fn parse_value_type(s: &str) -> Option<Type> {
+    match s {
+        "i32" => Some(Type::I32),
+        "i64" => Some(Type::I64),
+        "f32" => Some(Type::F32),
+        "f64" => Some(Type::F64),
+        _ => None,
+    }
+}
+
+fn check_signature(
+    module: &str,
+    field: &str,
+    declared: &Option<Vec<String>>,
+    actual: &[Type],
+    what: &str,
+) -> io::Result<()> {
+    let declared = match declared {
+        Some(declared) => declared,
+        None => return Ok(()),
+    };
+    let mut parsed = Vec::new();
+    for t in declared {
+        parsed.push(parse_value_type(t).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("import {}.{} has unknown {} type {}", module, field, what, t),
+            )
+        })?);
+    }
+    if parsed.as_slice() != actual {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "import {}.{} is declared with {} {:?} in the manifest, but the module uses {:?}",
+                module, field, what, parsed, actual
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Renames imported functions to the native symbols the manifest binds them to, and checks their signatures
+// Returns how each function index is reached, None for functions the module implements itself
+// Imports an earlier pass (--wasi, --abi-profile) bound keep that binding, unless the manifest names them too
+pub fn bind_imports(
+    manifest: &ImportManifest,
+    wasm_module: &mut WasmModule,
+    previous: Option<Vec<Option<ResolvedImport>>>,
+) -> io::Result<Vec<Option<ResolvedImport>>> {
+    let mut previous = previous.map(Vec::into_iter);
+    let mut resolved = Vec::new();
+    let mut unmapped = Vec::new();
+    for f in &mut wasm_module.functions {
+        let earlier = previous.as_mut().and_then(Iterator::next).flatten();
+        let (source, name, appended, ty) = match f {
+            Function::Imported {
+                source,
+                name,
+                appended,
+                ty,
+                ..
+            } => (source, name, appended, ty),
+            _ => {
+                resolved.push(None);
+                continue;
+            }
+        };
+
+        let binding = manifest.imports.get(source.as_str()).and_then(|fields| fields.get(name.as_str()));
+        let r = match binding {
+            Some(binding) => {
+                check_signature(source, name, &binding.params, &ty.params, "params")?;
+                check_signature(source, name, &binding.results, &ty.returns, "results")?;
+                if binding.pass_memory {
+                    // The wasm side calls the thunk, which keeps the old name but is never visible outside
+                    *appended = format!("{}_thunk", appended);
+                    ResolvedImport::PassMemory {
+                        symbol: binding.symbol.clone(),
+                    }
+                } else {
+                    *appended = binding.symbol.clone();
+                    ResolvedImport::Direct
+                }
+            }
+            // Direct is just the default, so only anything more specific counts as bound
+            None => match earlier {
+                Some(ResolvedImport::Direct) | None => {
+                    if manifest.unmapped == UnmappedPolicy::Error {
+                        unmapped.push(format!("{}.{}", source, name));
+                    }
+                    ResolvedImport::Trap
+                }
+                Some(earlier) => earlier,
+            },
+        };
+        resolved.push(Some(r));
+    }
+
+    if !unmapped.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("imports missing from the manifest: {}", unmapped.join(", ")),
+        ));
+    }
+    Ok(resolved)
+}
+
+fn native_func_type<'a>(m_ctx: &'a ModuleCtx, ty: &FuncType) -> &'a llvm::Type {
+    let ctx = m_ctx.llvm_ctx;
+    let return_type = match ty.returns.first() {
+        Some(t) => wasm_type_to_llvm_type(ctx, *t),
+        None => <()>::get_type(ctx),
+    };
+    let mut params: Vec<&llvm::Type> = vec![PointerType::new(<u8>::get_type(ctx))];
+    for t in &*ty.params {
+        params.push(wasm_type_to_llvm_type(ctx, *t));
+    }
+    FunctionType::new(return_type, &params).to_super()
+}
+
+// Gives bodies to the imports that need more than a plain declaration
+// The thunks keep the wasm signature, so direct calls, the table and devirtualization need no special casing
+pub fn generate_import_thunks(m_ctx: &ModuleCtx, resolved: &[Option<ResolvedImport>]) {
+    let ctx = m_ctx.llvm_ctx;
+    let b = Builder::new(ctx);
+    let first_param = if m_ctx.instance_type.is_some() { 1 } else { 0 };
+
+    for (n, r) in resolved.iter().enumerate() {
+        let (llvm_f, ref wasm_f) = m_ctx.functions[n];
+        match r {
+            None | Some(ResolvedImport::Direct) | Some(ResolvedImport::Runtime) => continue,
+            Some(ResolvedImport::PassMemory { symbol }) => {
+                let native_f = m_ctx
+                    .llvm_module
+                    .add_function(symbol, native_func_type(m_ctx, wasm_f.get_type()));
+                b.position_at_end(llvm_f.append("entry"));
+
+                let u8_ptr = PointerType::new(<u8>::get_type(ctx));
+                let memory_base = match (m_ctx.instance_type, m_ctx.linear_memory) {
+                    (Some(_), _) => {
+                        let instance: &Value = &*llvm_f[0];
+                        b.build_load(b.build_gep(
+                            instance,
+                            &[0u32.compile(ctx), MEMORY_BASE_FIELD.compile(ctx)],
+                        ))
+                    }
+                    (None, Some(linear_memory)) => b.build_bit_cast(linear_memory.to_super(), u8_ptr),
+                    (None, None) => unsafe { llvm::ffi::core::LLVMConstNull(u8_ptr.into()).into() },
+                };
+
+                let mut args = vec![memory_base];
+                for i in first_param..wasm_f.count_args() + first_param {
+                    args.push(&*llvm_f[i]);
+                }
+                let result = b.build_call(native_f, &args);
+                if wasm_f.has_return() {
+                    b.build_ret(result);
+                } else {
+                    b.build_ret_void();
+                }
+            }
+            Some(ResolvedImport::Lowered { call }) => generate_lowered_thunk(m_ctx, llvm_f, call),
+            Some(ResolvedImport::Inert) => {
+                b.position_at_end(llvm_f.append("entry"));
+                match wasm_f.get_type().returns.first() {
+                    Some(t) => {
+                        let zero = unsafe { llvm::ffi::core::LLVMConstNull(wasm_type_to_llvm_type(ctx, *t).into()) };
+                        b.build_ret(zero.into());
+                    }
+                    None => {
+                        b.build_ret_void();
+                    }
+                }
+            }
+            Some(ResolvedImport::Trap) => {
+                b.position_at_end(llvm_f.append("entry"));
+                b.build_call(get_stub_function(m_ctx, TRAP), &[]);
+                b.build_unreachable();
+            }
+        }
+        set_internal(llvm_f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::module_with_imports;
+
+    fn parse_manifest(text: &str) -> ImportManifest {
+        toml::from_str(text).unwrap()
+    }
+
+    fn appended_names(wasm_module: &WasmModule) -> Vec<&str> {
+        wasm_module
+            .functions
+            .iter()
+            .filter_map(|f| match f {
+                Function::Imported { appended, .. } => Some(appended.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn value_types_parse() {
+        assert_eq!(parse_value_type("i32"), Some(Type::I32));
+        assert_eq!(parse_value_type("i64"), Some(Type::I64));
+        assert_eq!(parse_value_type("f32"), Some(Type::F32));
+        assert_eq!(parse_value_type("f64"), Some(Type::F64));
+        assert_eq!(parse_value_type("I32"), None);
+        assert_eq!(parse_value_type("anyref"), None);
+    }
+
+    #[test]
+    fn signatures_are_checked_when_declared() {
+        let declared = Some(vec!["i32".to_string(), "f64".to_string()]);
+        assert!(check_signature("env", "f", &None, &[Type::I64], "params").is_ok());
+        assert!(check_signature("env", "f", &declared, &[Type::I32, Type::F64], "params").is_ok());
+        assert!(check_signature("env", "f", &declared, &[Type::I32], "params").is_err());
+        assert!(check_signature("env", "f", &Some(vec![]), &[], "results").is_ok());
+
+        let unknown = Some(vec!["u32".to_string()]);
+        let e = check_signature("env", "f", &unknown, &[Type::I32], "params").unwrap_err();
+        assert!(e.to_string().contains("unknown params type u32"));
+    }
+
+    #[test]
+    fn manifests_reject_unknown_fields_and_policies() {
+        assert!(toml::from_str::<ImportManifest>("unmapped = \"ignore\"").is_err());
+        assert!(toml::from_str::<ImportManifest>("[imports.env.f]\nsymbol = \"g\"\nsymbl = \"h\"").is_err());
+        assert!(toml::from_str::<ImportManifest>("[imports.env.f]\nparams = []").is_err());
+        assert_eq!(parse_manifest("").unmapped, UnmappedPolicy::Error);
+    }
+
+    #[test]
+    fn bound_imports_are_renamed() {
+        let mut m = module_with_imports(
+            &[
+                ("env", "f", &[Type::I32], &[Type::I32]),
+                ("env", "g", &[Type::I32, Type::I32], &[]),
+            ],
+            true,
+        );
+        let manifest = parse_manifest(
+            r#"
+            [imports.env.f]
+            symbol = "host_f"
+            params = ["i32"]
+            results = ["i32"]
+
+            [imports.env.g]
+            symbol = "host_g"
+            pass_memory = true
+            "#,
+        );
+        let resolved = bind_imports(&manifest, &mut m, None).unwrap();
+        assert_eq!(appended_names(&m), vec!["host_f", "env_g_thunk"]);
+        match (&resolved[0], &resolved[1]) {
+            (Some(ResolvedImport::Direct), Some(ResolvedImport::PassMemory { symbol })) => {
+                assert_eq!(symbol, "host_g")
+            }
+            _ => panic!("f should be called directly and g through a thunk"),
+        }
+    }
+
+    #[test]
+    fn mismatched_signatures_are_rejected() {
+        let mut m = module_with_imports(&[("env", "f", &[Type::I64], &[])], false);
+        let manifest = parse_manifest("[imports.env.f]\nsymbol = \"host_f\"\nparams = [\"i32\"]");
+        let e = bind_imports(&manifest, &mut m, None).err().unwrap();
+        assert!(e.to_string().contains("env.f"));
+    }
+
+    #[test]
+    fn unmapped_imports_follow_the_policy() {
+        let imports: &[(&str, &str, &[Type], &[Type])] =
+            &[("env", "f", &[], &[]), ("env", "g", &[], &[]), ("other", "h", &[], &[])];
+        let mapped_f = "[imports.env.f]\nsymbol = \"host_f\"\n";
+
+        let mut m = module_with_imports(imports, false);
+        let e = bind_imports(&parse_manifest(mapped_f), &mut m, None).err().unwrap();
+        assert!(e.to_string().contains("env.g, other.h"));
+
+        let mut m = module_with_imports(imports, false);
+        let trapping = format!("unmapped = \"trap\"\n{}", mapped_f);
+        let resolved = bind_imports(&parse_manifest(&trapping), &mut m, None).unwrap();
+        match (&resolved[1], &resolved[2]) {
+            (Some(ResolvedImport::Trap), Some(ResolvedImport::Trap)) => {}
+            _ => panic!("unmapped imports should trap"),
+        }
+    }
+
+    #[test]
+    fn earlier_bindings_are_kept_unless_the_manifest_overrides_them() {
+        let imports: &[(&str, &str, &[Type], &[Type])] = &[("env", "f", &[], &[]), ("env", "g", &[], &[])];
+        let mut m = module_with_imports(imports, false);
+        let previous = vec![Some(ResolvedImport::Runtime), Some(ResolvedImport::Inert)];
+        let manifest = parse_manifest("[imports.env.g]\nsymbol = \"host_g\"\n");
+        let resolved = bind_imports(&manifest, &mut m, Some(previous)).unwrap();
+        match (&resolved[0], &resolved[1]) {
+            (Some(ResolvedImport::Runtime), Some(ResolvedImport::Direct)) => {}
+            _ => panic!("f should stay bound to the runtime and g follow the manifest"),
+        }
+
+        // Direct is only the default, so it does not count as bound
+        let mut m = module_with_imports(&imports[..1], false);
+        let empty = parse_manifest("");
+        assert!(bind_imports(&empty, &mut m, Some(vec![Some(ResolvedImport::Direct)])).is_err());
+    }
+}
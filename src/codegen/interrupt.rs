@@ -0,0 +1,79 @@
+use llvm::BasicBlock;
+use llvm::Builder;
+use llvm::Context as LLVMCtx;
+use llvm::GlobalVariable;
+use llvm::Module as LLVMModule;
+use llvm::Predicate;
+use llvm::Type;
+
+use crate::codegen::function::FunctionCtx;
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::ModuleCtx;
+use crate::Opt;
+
+// Both globals are defined by the runtime, a timer thread bumps the epoch and the host sets the deadline
+// They are pointer sized, so reading the epoch never tears on 32 bit targets
+pub const INTERRUPT_EPOCH_GLOBAL: &str = "wasm_interrupt_epoch";
+pub const EPOCH_DEADLINE_GLOBAL: &str = "wasm_epoch_deadline";
+// What the timer calls to bump the epoch
+pub const INCREMENT_EPOCH: &str = "wasm_increment_epoch";
+
+pub fn insert_interrupt_globals<'a>(
+    opt: &Opt,
+    llvm_ctx: &'a LLVMCtx,
+    llvm_module: &'a LLVMModule,
+) -> (Option<&'a GlobalVariable>, Option<&'a GlobalVariable>) {
+    if !opt.interruptible {
+        return (None, None);
+    }
+
+    // The data layout is already the target's, see process_to_llvm
+    let epoch_type: &Type = unsafe {
+        use llvm::ffi::target;
+        target::LLVMIntPtrTypeInContext(llvm_ctx.into(), target::LLVMGetModuleDataLayout(llvm_module.into())).into()
+    };
+    let epoch = llvm_module.add_global(INTERRUPT_EPOCH_GLOBAL, epoch_type);
+    let deadline = llvm_module.add_global(EPOCH_DEADLINE_GLOBAL, epoch_type);
+    (Some(epoch), Some(deadline))
+}
+
+// Calls the epoch hook if the deadline has passed
+// Returns the basic block the rest of the code should be emitted into
+pub fn build_interrupt_check<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    basic_block: &'a BasicBlock,
+) -> &'a BasicBlock {
+    let (epoch, deadline) = match (m_ctx.interrupt_epoch, m_ctx.epoch_deadline) {
+        (Some(epoch), Some(deadline)) => (epoch, deadline),
+        _ => return basic_block,
+    };
+
+    // The epoch is written by another thread, so the load must be atomic and not hoisted out of the loop
+    // Nothing is synchronized through it, so monotonic is enough
+    let current = b.build_load(epoch);
+    unsafe {
+        use llvm::ffi::{core, target};
+        let data_layout = target::LLVMGetModuleDataLayout(m_ctx.llvm_module.into());
+        let size = target::LLVMABISizeOfType(data_layout, core::LLVMTypeOf(current.into()));
+        core::LLVMSetVolatile(current.into(), 1);
+        core::LLVMSetOrdering(current.into(), llvm::ffi::LLVMAtomicOrdering::LLVMAtomicOrderingMonotonic);
+        // Atomic loads must say how they are aligned, and a global of the type always is
+        core::LLVMSetAlignment(current.into(), size as u32);
+    }
+    let limit = b.build_load(deadline);
+    let expired = b.build_unsigned_cmp(current, limit, Predicate::GreaterThanOrEqual);
+
+    // The hook can yield back to the host and return, or trap
+    let expired_bb = f_ctx.generate_block();
+    let continue_bb = f_ctx.generate_block();
+    b.build_cond_br(expired, expired_bb, Some(continue_bb));
+
+    b.position_at_end(expired_bb);
+    b.build_call(get_stub_function(m_ctx, EPOCH_EXPIRED), &[]);
+    b.build_br(continue_bb);
+
+    b.position_at_end(continue_bb);
+    continue_bb
+}
@@ -0,0 +1,142 @@
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+use llvm::ffi::prelude::LLVMModuleRef;
+use llvm::ffi::prelude::LLVMValueRef;
+use llvm::ffi::LLVMLinkage;
+use llvm::Module as LLVMModule;
+
+use crate::codegen::interrupt::EPOCH_DEADLINE_GLOBAL;
+use crate::codegen::interrupt::INCREMENT_EPOCH;
+use crate::codegen::interrupt::INTERRUPT_EPOCH_GLOBAL;
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::stack_guard::SET_STACK_LIMIT;
+use crate::codegen::stack_guard::STACK_LIMIT_GLOBAL;
+use crate::Opt;
+
+// The runtime entry point has to stay visible, everything else the runtime defines becomes private to the object
+const RUNTIME_ENTRY_POINTS: &[&str] = &["main"];
+
+// With --runtime-globals the header sends the host to these, so they have to stay reachable
+const RUNTIME_GLOBAL_ACCESSORS: &[&str] = &[
+    GET_GLOBAL_I32,
+    SET_GLOBAL_I32,
+    GET_GLOBAL_I64,
+    SET_GLOBAL_I64,
+    GET_GLOBAL_F32,
+    SET_GLOBAL_F32,
+    GET_GLOBAL_F64,
+    SET_GLOBAL_F64,
+];
+
+fn io_error(message: *mut std::os::raw::c_char) -> io::Error {
+    let text = unsafe {
+        let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+        llvm::ffi::core::LLVMDisposeMessage(message);
+        text
+    };
+    io::Error::new(io::ErrorKind::Other, text)
+}
+
+unsafe fn defined_symbols(m: LLVMModuleRef) -> Vec<CString> {
+    use llvm::ffi::core;
+
+    let mut names = Vec::new();
+    // Weak definitions are defaults another object is meant to override, internalizing them would make them win
+    let mut push_if_defined = |v: LLVMValueRef| {
+        let overridable = match core::LLVMGetLinkage(v) {
+            LLVMLinkage::LLVMWeakAnyLinkage
+            | LLVMLinkage::LLVMWeakODRLinkage
+            | LLVMLinkage::LLVMLinkOnceAnyLinkage
+            | LLVMLinkage::LLVMLinkOnceODRLinkage => true,
+            _ => false,
+        };
+        if core::LLVMIsDeclaration(v) == 0 && !overridable {
+            names.push(CStr::from_ptr(core::LLVMGetValueName(v)).to_owned());
+        }
+    };
+
+    let mut f = core::LLVMGetFirstFunction(m);
+    while !f.is_null() {
+        push_if_defined(f);
+        f = core::LLVMGetNextFunction(f);
+    }
+    let mut g = core::LLVMGetFirstGlobal(m);
+    while !g.is_null() {
+        push_if_defined(g);
+        g = core::LLVMGetNextGlobal(g);
+    }
+    names
+}
+
+// With --interruptible the host's timer and its deadline live outside the object
+const RUNTIME_EPOCH_SYMBOLS: &[&str] = &[INTERRUPT_EPOCH_GLOBAL, EPOCH_DEADLINE_GLOBAL, INCREMENT_EPOCH];
+
+// With --stack-limit-bytes and --thread-local, every host thread sets its own limit
+const RUNTIME_STACK_LIMIT_SYMBOLS: &[&str] = &[STACK_LIMIT_GLOBAL, SET_STACK_LIMIT];
+
+fn keeps_visible(opt: &Opt, name: &CStr) -> bool {
+    let name = name.to_bytes();
+    RUNTIME_ENTRY_POINTS.iter().any(|e| e.as_bytes() == name)
+        || (opt.use_runtime_global_handling && RUNTIME_GLOBAL_ACCESSORS.iter().any(|e| e.as_bytes() == name))
+        || (opt.interruptible && RUNTIME_EPOCH_SYMBOLS.iter().any(|e| e.as_bytes() == name))
+        || (opt.stack_limit_bytes.is_some() && RUNTIME_STACK_LIMIT_SYMBOLS.iter().any(|e| e.as_bytes() == name))
+        || opt.keep_symbols.iter().any(|e| e.as_bytes() == name)
+}
+
+// Links a runtime bitcode file into the generated module, and internalizes what it defines
+// After this, optimization can inline the runtime's memory accessors straight into the wasm code
+pub fn link_runtime(opt: &Opt, llvm_module: &LLVMModule, runtime_path: &Path) -> io::Result<()> {
+    let module_ref: LLVMModuleRef = llvm_module.into();
+    let c_path = CString::new(runtime_path.to_string_lossy().into_owned()).unwrap();
+
+    unsafe {
+        use llvm::ffi::core;
+
+        let mut buffer = ptr::null_mut();
+        let mut message = ptr::null_mut();
+        if core::LLVMCreateMemoryBufferWithContentsOfFile(c_path.as_ptr(), &mut buffer, &mut message) != 0 {
+            return Err(io_error(message));
+        }
+
+        let mut runtime_module = ptr::null_mut();
+        let llvm_ctx = core::LLVMGetModuleContext(module_ref);
+        let parse_failed =
+            llvm::ffi::bit_reader::LLVMParseBitcodeInContext2(llvm_ctx, buffer, &mut runtime_module);
+        core::LLVMDisposeMemoryBuffer(buffer);
+        if parse_failed != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a valid LLVM bitcode file", runtime_path.display()),
+            ));
+        }
+
+        let runtime_symbols = defined_symbols(runtime_module);
+
+        // This consumes the runtime module, even on failure
+        if llvm::ffi::linker::LLVMLinkModules2(module_ref, runtime_module) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to link {} into the module", runtime_path.display()),
+            ));
+        }
+
+        for name in runtime_symbols {
+            if keeps_visible(opt, &name) {
+                continue;
+            }
+            let mut symbol = core::LLVMGetNamedFunction(module_ref, name.as_ptr());
+            if symbol.is_null() {
+                symbol = core::LLVMGetNamedGlobal(module_ref, name.as_ptr());
+            }
+            if !symbol.is_null() {
+                core::LLVMSetLinkage(symbol, LLVMLinkage::LLVMInternalLinkage);
+            }
+        }
+    }
+
+    Ok(())
+}
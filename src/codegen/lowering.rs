@@ -0,0 +1,284 @@
+use llvm::Builder;
+use llvm::Compile;
+use llvm::Context;
+use llvm::FunctionType;
+use llvm::PointerType;
+use llvm::Predicate;
+use llvm::Sub;
+use llvm::Value;
+
+use wasmparser::FuncType;
+use wasmparser::Type;
+
+use crate::codegen::abi::AbiType;
+use crate::codegen::memory::MEMORY_ACCESSOR;
+use crate::codegen::memory::MEMORY_SIZE_ACCESSOR;
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::type_conversions::wasm_type_to_llvm_type;
+use crate::codegen::ModuleCtx;
+
+// How a parameter of a lowered import crosses into the runtime
+#[derive(Clone, Copy, Debug)]
+pub enum LoweredParam {
+    // Passed through unchanged
+    Scalar(Type),
+    // An offset to a fixed size struct, checked and passed as a host pointer
+    Pointer(u32),
+    // An offset followed by a count of elements of the given size, checked and passed as a host pointer and the count
+    Buffer(u32),
+    // An offset whose extent only the runtime knows, passed through for it to check against the memory size
+    Offset,
+}
+
+use self::LoweredParam::*;
+
+// An import the runtime implements against linear memory, such as a WASI call or a toolchain helper
+#[derive(Debug)]
+pub struct LoweredCall {
+    pub name: &'static str,
+    // The runtime function implementing the call
+    pub symbol: &'static str,
+    pub params: &'static [LoweredParam],
+    pub result: Option<Type>,
+    // Returned when a checked pointer is out of bounds, without one the call traps instead
+    pub fault_errno: Option<i32>,
+}
+
+impl LoweredCall {
+    fn wasm_params(&self) -> Vec<Type> {
+        let mut params = Vec::new();
+        for p in self.params {
+            match p {
+                Scalar(t) => params.push(*t),
+                Pointer(_) | Offset => params.push(Type::I32),
+                Buffer(_) => params.extend(&[Type::I32, Type::I32]),
+            }
+        }
+        params
+    }
+
+    // Whether an import with this wasm signature can be lowered onto the call
+    pub fn matches(&self, ty: &FuncType) -> bool {
+        *ty.params == *self.wasm_params() && *ty.returns == *self.result.into_iter().collect::<Vec<_>>()
+    }
+
+    // The runtime side always gets the memory base and size first, for the offsets nested in what it is passed
+    pub fn native_params(&self) -> Vec<AbiType> {
+        let mut params = vec![AbiType::Bytes, AbiType::U32];
+        for p in self.params {
+            match p {
+                Scalar(Type::I64) => params.push(AbiType::I64),
+                Scalar(Type::F32) => params.push(AbiType::F32),
+                Scalar(Type::F64) => params.push(AbiType::F64),
+                Scalar(_) => params.push(AbiType::I32),
+                Pointer(_) => params.push(AbiType::Bytes),
+                Buffer(_) => params.extend(&[AbiType::Bytes, AbiType::U32]),
+                Offset => params.push(AbiType::U32),
+            }
+        }
+        params
+    }
+}
+
+fn native_lowered_type<'a>(m_ctx: &'a ModuleCtx, call: &LoweredCall) -> &'a llvm::Type {
+    let ctx = m_ctx.llvm_ctx;
+    let u8_ptr = PointerType::new(<u8>::get_type(ctx));
+    let mut params: Vec<&llvm::Type> = vec![u8_ptr, <u32>::get_type(ctx)];
+    for p in call.params {
+        match p {
+            Scalar(t) => params.push(wasm_type_to_llvm_type(ctx, *t)),
+            Pointer(_) => params.push(u8_ptr),
+            Buffer(_) => params.extend(&[u8_ptr, <u32>::get_type(ctx)]),
+            Offset => params.push(<u32>::get_type(ctx)),
+        }
+    }
+    let return_type = match call.result {
+        Some(t) => wasm_type_to_llvm_type(ctx, t),
+        None => <()>::get_type(ctx),
+    };
+    FunctionType::new(return_type, &params).to_super()
+}
+
+// Whether `extent` bytes from the u32 `offset` fit below the u64 `memory_limit`
+// Done in 64 bits, so an offset near the top of the address space cannot wrap around into bounds
+fn build_in_bounds<'a>(
+    ctx: &'a Context,
+    b: &'a Builder,
+    offset: &'a Value,
+    extent: &'a Value,
+    memory_limit: &'a Value,
+) -> &'a Value {
+    let end = b.build_add(b.build_zext(offset, <u64>::get_type(ctx)), extent);
+    b.build_unsigned_cmp(end, memory_limit, Predicate::LessThanOrEqual)
+}
+
+// The size in bytes of `count` elements, as a u64 so no u32 count can overflow it
+fn build_buffer_extent<'a>(ctx: &'a Context, b: &'a Builder, count: &'a Value, element_size: u32) -> &'a Value {
+    b.build_mul(b.build_zext(count, <u64>::get_type(ctx)), (element_size as u64).compile(ctx))
+}
+
+// Checks every pointer argument lies within linear memory, then calls the runtime with host pointers
+pub fn generate_lowered_thunk(m_ctx: &ModuleCtx, llvm_f: &llvm::Function, call: &LoweredCall) {
+    let ctx = m_ctx.llvm_ctx;
+    let native_f = m_ctx.llvm_module.add_function(call.symbol, native_lowered_type(m_ctx, call));
+    let b = Builder::new(ctx);
+    b.position_at_end(llvm_f.append("entry"));
+
+    let first_param = if m_ctx.instance_type.is_some() { 1 } else { 0 };
+    let accessor_args: Vec<&Value> = if m_ctx.instance_type.is_some() {
+        vec![&*llvm_f[0]]
+    } else {
+        Vec::new()
+    };
+    let memory_base = b.build_call(
+        m_ctx.llvm_module.get_function(MEMORY_ACCESSOR).unwrap(),
+        &accessor_args,
+    );
+    let memory_size = b.build_call(
+        m_ctx.llvm_module.get_function(MEMORY_SIZE_ACCESSOR).unwrap(),
+        &accessor_args,
+    );
+    let memory_limit = b.build_zext(memory_size, <u64>::get_type(ctx));
+
+    let mut args = vec![memory_base, memory_size];
+    let mut in_bounds: Option<&Value> = None;
+    let mut check = |offset: &Value, extent: &Value| {
+        let ok = build_in_bounds(ctx, &b, offset, extent, memory_limit);
+        in_bounds = Some(match in_bounds {
+            Some(previous) => b.build_and(previous, ok),
+            None => ok,
+        });
+        b.build_gep(memory_base, &[b.build_zext(offset, <u64>::get_type(ctx))])
+    };
+
+    let mut i = first_param;
+    for p in call.params {
+        let param: &Value = &*llvm_f[i];
+        match p {
+            Scalar(_) | Offset => args.push(param),
+            Pointer(size) => args.push(check(param, (*size as u64).compile(ctx))),
+            Buffer(element_size) => {
+                let count: &Value = &*llvm_f[i + 1];
+                args.push(check(param, build_buffer_extent(ctx, &b, count, *element_size)));
+                args.push(count);
+                i += 1;
+            }
+        }
+        i += 1;
+    }
+
+    if let Some(in_bounds) = in_bounds {
+        let fault_bb = llvm_f.append("fault");
+        let call_bb = llvm_f.append("call");
+        b.build_cond_br(in_bounds, call_bb, Some(fault_bb));
+        b.position_at_end(fault_bb);
+        match call.fault_errno {
+            Some(errno) => {
+                b.build_ret(errno.compile(ctx));
+            }
+            None => {
+                b.build_call(get_stub_function(m_ctx, TRAP), &[]);
+                b.build_unreachable();
+            }
+        }
+        b.position_at_end(call_bb);
+    }
+
+    let result = b.build_call(native_f, &args);
+    if call.result.is_some() {
+        b.build_ret(result);
+    } else {
+        b.build_ret_void();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMORY_SIZE: u64 = 65536;
+
+    // The builder folds constant operands, so the checks evaluate without running anything
+    fn in_bounds(offset: u32, extent: u64) -> bool {
+        let ctx = Context::new();
+        let b = Builder::new(&ctx);
+        let ok = build_in_bounds(&ctx, &b, offset.compile(&ctx), extent.compile(&ctx), MEMORY_SIZE.compile(&ctx));
+        unsafe { llvm::ffi::core::LLVMConstIntGetZExtValue(ok.into()) != 0 }
+    }
+
+    fn buffer_extent(count: u32, element_size: u32) -> u64 {
+        let ctx = Context::new();
+        let b = Builder::new(&ctx);
+        let extent = build_buffer_extent(&ctx, &b, count.compile(&ctx), element_size);
+        unsafe { llvm::ffi::core::LLVMConstIntGetZExtValue(extent.into()) }
+    }
+
+    #[test]
+    fn bounds_checks_allow_exactly_linear_memory() {
+        assert!(in_bounds(0, 0));
+        assert!(in_bounds(0, MEMORY_SIZE));
+        assert!(in_bounds(MEMORY_SIZE as u32 - 8, 8));
+        assert!(in_bounds(MEMORY_SIZE as u32, 0));
+        assert!(!in_bounds(MEMORY_SIZE as u32 - 7, 8));
+        assert!(!in_bounds(0, MEMORY_SIZE + 1));
+        assert!(!in_bounds(MEMORY_SIZE as u32 + 1, 0));
+    }
+
+    #[test]
+    fn bounds_checks_do_not_wrap() {
+        assert!(!in_bounds(u32::max_value(), 1));
+        assert!(!in_bounds(u32::max_value(), 8));
+        assert!(!in_bounds(1, u32::max_value() as u64));
+        assert!(!in_bounds(8, buffer_extent(u32::max_value(), 8)));
+    }
+
+    #[test]
+    fn buffer_extents_are_computed_in_64_bits() {
+        assert_eq!(buffer_extent(0, 8), 0);
+        assert_eq!(buffer_extent(3, 8), 24);
+        assert_eq!(buffer_extent(u32::max_value(), 1), u32::max_value() as u64);
+        assert_eq!(buffer_extent(u32::max_value(), 8), u32::max_value() as u64 * 8);
+        // 2^29 iovecs of 8 bytes would wrap to 0 in 32 bits
+        assert_eq!(buffer_extent(1 << 29, 8), 1 << 32);
+    }
+
+    const FD_WRITE: LoweredCall = LoweredCall {
+        name: "fd_write",
+        symbol: "wasi_fd_write",
+        params: &[Scalar(Type::I32), Buffer(8), Pointer(4)],
+        result: Some(Type::I32),
+        fault_errno: Some(21),
+    };
+
+    fn func_type(params: &[Type], returns: &[Type]) -> FuncType {
+        FuncType {
+            form: Type::Func,
+            params: params.to_vec().into_boxed_slice(),
+            returns: returns.to_vec().into_boxed_slice(),
+        }
+    }
+
+    #[test]
+    fn buffers_take_an_offset_and_a_count() {
+        let i32s = [Type::I32; 4];
+        assert!(FD_WRITE.matches(&func_type(&i32s, &[Type::I32])));
+        assert!(!FD_WRITE.matches(&func_type(&i32s[..3], &[Type::I32])));
+        assert!(!FD_WRITE.matches(&func_type(&i32s, &[])));
+        assert!(!FD_WRITE.matches(&func_type(&[Type::I32, Type::I64, Type::I32, Type::I32], &[Type::I32])));
+    }
+
+    #[test]
+    fn the_runtime_gets_the_memory_first() {
+        assert_eq!(
+            FD_WRITE.native_params(),
+            vec![
+                AbiType::Bytes,
+                AbiType::U32,
+                AbiType::I32,
+                AbiType::Bytes,
+                AbiType::U32,
+                AbiType::Bytes
+            ]
+        );
+    }
+}
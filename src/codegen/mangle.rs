@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use serde::Serialize;
+use wasmparser::Type;
+
+use crate::codegen::abi::import_native_signature;
+use crate::codegen::abi::AbiType;
+use crate::codegen::fuel::FUEL_GET;
+use crate::codegen::fuel::FUEL_GLOBAL;
+use crate::codegen::fuel::FUEL_SET;
+use crate::codegen::fuel::INITIAL_FUEL;
+use crate::codegen::globals::HEAP_BASE_INDEX;
+use crate::codegen::globals::POPULATE_GLOBALS;
+use crate::codegen::imports::native_import_symbol;
+use crate::codegen::imports::ResolvedImport;
+use crate::codegen::interrupt::EPOCH_DEADLINE_GLOBAL;
+use crate::codegen::interrupt::INTERRUPT_EPOCH_GLOBAL;
+use crate::codegen::memory::MEMORY_ACCESSOR;
+use crate::codegen::memory::MEMORY_SIZE_ACCESSOR;
+use crate::codegen::memory::MEMORY_IMAGE_TAIL;
+use crate::codegen::memory::MEMORY_IMAGE_CHECK;
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::stack_guard::CALL_DEPTH_GLOBAL;
+use crate::codegen::stack_guard::STACK_LIMIT_BYTES_GLOBAL;
+use crate::codegen::stack_guard::STACK_LIMIT_GLOBAL;
+use crate::codegen::symbols::defined_symbol;
+use crate::codegen::table::INLINE_TABLE;
+use crate::codegen::thread_local::THREAD_INIT;
+use crate::codegen::thread_local::THREAD_TEARDOWN;
+use crate::codegen::vmctx::INSTANCE_INIT;
+use crate::codegen::vmctx::INSTANCE_SIZE;
+use crate::wasm::Export;
+use crate::wasm::Function;
+use crate::wasm::Global;
+use crate::wasm::WasmModule;
+use crate::Opt;
+
+const MANGLED_IMPORT_PREFIX: &str = "wasm_import_";
+const MANGLED_EXPORT_PREFIX: &str = "wasm_export_";
+// Escaping only ever produces `__` and `_xHH`, so `_s` can only be the separator
+const MANGLED_SEPARATOR: &str = "_s";
+
+// Symbols the runtime or libc define, which nothing from the wasm module may take
+const RUNTIME_SYMBOLS: &[&str] = &[
+    "main",
+    "memcpy",
+    "memmove",
+    "memset",
+    "memcmp",
+    "malloc",
+    "calloc",
+    "realloc",
+    "free",
+    "abort",
+    "exit",
+    "printf",
+    FI32_FLOOR,
+    INITIALIZE_REGION_STUB,
+    TABLE_ADD,
+    TABLE_FETCH,
+    GET_GLOBAL_I32,
+    SET_GLOBAL_I32,
+    GET_GLOBAL_I64,
+    SET_GLOBAL_I64,
+    GET_GLOBAL_F32,
+    SET_GLOBAL_F32,
+    GET_GLOBAL_F64,
+    SET_GLOBAL_F64,
+    OUT_OF_FUEL,
+    EPOCH_EXPIRED,
+    STACK_LIMIT_GLOBAL,
+    INTERRUPT_EPOCH_GLOBAL,
+    EPOCH_DEADLINE_GLOBAL,
+];
+
+// Symbols we generate ourselves, these get the --symbol-prefix like everything else we define
+const GENERATED_SYMBOLS: &[&str] = &[
+    "linear_memory",
+    "populate_memory",
+    "populate_table",
+    POPULATE_GLOBALS,
+    HEAP_BASE_INDEX,
+    "starting_pages",
+    "max_pages",
+    INLINE_TABLE,
+    MEMORY_ACCESSOR,
+    MEMORY_SIZE_ACCESSOR,
+    MEMORY_IMAGE_TAIL,
+    MEMORY_IMAGE_CHECK,
+    FUEL_GLOBAL,
+    FUEL_GET,
+    FUEL_SET,
+    INITIAL_FUEL,
+    CALL_DEPTH_GLOBAL,
+    STACK_LIMIT_BYTES_GLOBAL,
+    INSTANCE_SIZE,
+    INSTANCE_INIT,
+    THREAD_INIT,
+    THREAD_TEARDOWN,
+];
+
+// Letters and digits pass through, `_` doubles, and every other byte becomes `_xHH`
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => escaped.push(byte as char),
+            b'_' => escaped.push_str("__"),
+            _ => escaped.push_str(&format!("_x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+fn lowercase_hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        _ => None,
+    }
+}
+
+// Undoes `escape` up to the first separator, returning the text after it if there was one
+fn unescape(s: &str) -> Option<(String, Option<&str>)> {
+    let bytes = s.as_bytes();
+    let mut unescaped = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'_' {
+            unescaped.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1)? {
+            b'_' => {
+                unescaped.push(b'_');
+                i += 2;
+            }
+            // Only the exact form `escape` writes, so every symbol has one spelling
+            b'x' => {
+                let high = lowercase_hex_digit(*bytes.get(i + 2)?)?;
+                let low = lowercase_hex_digit(*bytes.get(i + 3)?)?;
+                let byte = high << 4 | low;
+                if byte.is_ascii_alphanumeric() || byte == b'_' {
+                    return None;
+                }
+                unescaped.push(byte);
+                i += 4;
+            }
+            b's' => return Some((String::from_utf8(unescaped).ok()?, Some(&s[i + 2..]))),
+            _ => return None,
+        }
+    }
+    Some((String::from_utf8(unescaped).ok()?, None))
+}
+
+fn mangle_import(module: &str, field: &str) -> String {
+    format!("{}{}{}{}", MANGLED_IMPORT_PREFIX, escape(module), MANGLED_SEPARATOR, escape(field))
+}
+
+fn demangle_import(symbol: &str) -> Option<(String, String)> {
+    let (module, rest) = unescape(symbol.strip_prefix(MANGLED_IMPORT_PREFIX)?)?;
+    match unescape(rest?)? {
+        (field, None) => Some((module, field)),
+        _ => None,
+    }
+}
+
+fn mangle_export(name: &str) -> String {
+    format!("{}{}", MANGLED_EXPORT_PREFIX, escape(name))
+}
+
+fn demangle_export(symbol: &str) -> Option<String> {
+    match unescape(symbol.strip_prefix(MANGLED_EXPORT_PREFIX)?)? {
+        (name, None) => Some(name),
+        _ => None,
+    }
+}
+
+// Names imports and exports, either with the plain `module_field` and export names, or with --mangle
+pub fn assign_symbols(opt: &Opt, wasm_module: &mut WasmModule) {
+    if opt.mangle {
+        for f in &mut wasm_module.functions {
+            if let Function::Imported {
+                source,
+                name,
+                appended,
+                ..
+            } = f
+            {
+                *appended = mangle_import(source, name);
+                debug_assert_eq!(demangle_import(appended), Some((source.clone(), name.clone())));
+            }
+        }
+        for g in &mut wasm_module.globals {
+            if let Global::Imported {
+                source, field, name, ..
+            } = g
+            {
+                *name = mangle_import(source, field);
+            }
+        }
+    }
+
+    for e in &wasm_module.exports {
+        let export_symbol = |name: &str| {
+            if opt.mangle {
+                let symbol = mangle_export(name);
+                debug_assert_eq!(demangle_export(&symbol).as_ref().map(String::as_str), Some(name));
+                symbol
+            } else {
+                name.to_string()
+            }
+        };
+        match e {
+            Export::Function { index, name } => {
+                wasm_module.functions[*index].set_name(export_symbol(name));
+            }
+            Export::Global { index, name } => {
+                wasm_module.globals[*index].set_name(export_symbol(name));
+            }
+            // Exporting memory is meaningless in our native embedding
+            Export::Memory { .. } => {}
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MappedImport {
+    kind: &'static str,
+    module: String,
+    field: String,
+    symbol: String,
+}
+
+#[derive(Serialize)]
+struct MappedExport {
+    kind: &'static str,
+    name: String,
+    symbol: String,
+}
+
+// The wasm names behind every native symbol, for host code and tools
+#[derive(Serialize)]
+pub struct SymbolMap {
+    imports: Vec<MappedImport>,
+    exports: Vec<MappedExport>,
+}
+
+// How something comes to have a symbol, which decides whether it may share it
+#[derive(PartialEq)]
+enum Claim {
+    // Defined by the runtime or libc, whatever its signature
+    Runtime,
+    // Defined by the module itself
+    Definition,
+    // Declared by the module for the host to define, with what it is called with
+    Function(Vec<AbiType>, Option<AbiType>),
+    Global(Type),
+}
+
+struct SymbolOwners {
+    owners: HashMap<String, (String, Claim)>,
+    // Without --mangle exports keep their wasm names, and modules routinely export `main` or `malloc` themselves
+    // So a clash with the runtime is only an error once mangling promises there are none
+    strict_runtime: bool,
+}
+
+impl Claim {
+    fn is_declaration(&self) -> bool {
+        match self {
+            Claim::Function(..) | Claim::Global(..) => true,
+            Claim::Runtime | Claim::Definition => false,
+        }
+    }
+}
+
+impl SymbolOwners {
+    fn claim(&mut self, symbol: String, owner: String, claim: Claim) -> io::Result<()> {
+        if let Some((existing, existing_claim)) = self.owners.get(&symbol) {
+            // Several imports may be bound to one host function, and any import may be bound to the runtime
+            let shared = match existing_claim {
+                Claim::Runtime => claim.is_declaration(),
+                existing_claim => existing_claim.is_declaration() && *existing_claim == claim,
+            };
+            if shared {
+                return Ok(());
+            }
+            if *existing_claim == Claim::Runtime && !self.strict_runtime {
+                warn!("{} is also the {}, which only links if the runtime does not define it", owner, existing);
+                return Ok(());
+            }
+            let message = if existing_claim.is_declaration() && claim.is_declaration() {
+                format!("{} and {} are both bound to {}, but with different signatures", existing, owner, symbol)
+            } else {
+                format!(
+                    "{} and {} would both be the symbol {}, consider --mangle or --symbol-prefix",
+                    existing, owner, symbol
+                )
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        self.owners.insert(symbol, (owner, claim));
+        Ok(())
+    }
+}
+
+// Works out the final native symbol of every import and export, after prefixing and the import manifest
+// Fails if two definitions would end up with the same symbol, since LLVM would otherwise quietly rename one
+// Declarations may share a symbol when they agree on its signature, clashes with the runtime are only warned about without --mangle
+pub fn build_symbol_map(
+    opt: &Opt,
+    wasm_module: &WasmModule,
+    resolved_imports: Option<&[Option<ResolvedImport>]>,
+) -> io::Result<SymbolMap> {
+    let defined = |name: &str| defined_symbol(opt, name);
+
+    let mut owners = SymbolOwners {
+        owners: HashMap::new(),
+        strict_runtime: opt.mangle,
+    };
+    for &symbol in RUNTIME_SYMBOLS {
+        owners.claim(symbol.to_string(), format!("runtime symbol {}", symbol), Claim::Runtime)?;
+    }
+    for &symbol in GENERATED_SYMBOLS {
+        owners.claim(defined(symbol), format!("generated symbol {}", symbol), Claim::Definition)?;
+    }
+
+    let mut symbol_map = SymbolMap {
+        imports: Vec::new(),
+        exports: Vec::new(),
+    };
+
+    for (n, f) in wasm_module.functions.iter().enumerate() {
+        match f {
+            Function::Imported {
+                source,
+                name,
+                appended,
+                ty,
+                ..
+            } => {
+                let resolved = resolved_imports.and_then(|r| r[n].as_ref());
+                // A trap stub is ours, and never leaves the object
+                let symbol = match native_import_symbol(appended, resolved) {
+                    Some(symbol) => symbol.to_string(),
+                    None => continue,
+                };
+                let (params, result) = import_native_signature(opt, ty, resolved);
+                owners.claim(symbol.clone(), format!("import {}.{}", source, name), Claim::Function(params, result))?;
+                symbol_map.imports.push(MappedImport {
+                    kind: "function",
+                    module: source.clone(),
+                    field: name.clone(),
+                    symbol,
+                });
+            }
+            _ => owners.claim(defined(f.get_name()), format!("function {}", n), Claim::Definition)?,
+        }
+    }
+
+    for (n, g) in wasm_module.globals.iter().enumerate() {
+        match g {
+            Global::Imported {
+                source,
+                field,
+                name,
+                content_type,
+                ..
+            } => {
+                owners.claim(name.clone(), format!("import {}.{}", source, field), Claim::Global(*content_type))?;
+                symbol_map.imports.push(MappedImport {
+                    kind: "global",
+                    module: source.clone(),
+                    field: field.clone(),
+                    symbol: name.clone(),
+                });
+            }
+            Global::InModule { generated_name, .. } => {
+                owners.claim(defined(generated_name), format!("global {}", n), Claim::Definition)?
+            }
+        }
+    }
+
+    for e in &wasm_module.exports {
+        let (kind, name, symbol) = match e {
+            Export::Function { index, name } => ("function", name, defined(wasm_module.functions[*index].get_name())),
+            Export::Global { index, name } => match &wasm_module.globals[*index] {
+                Global::InModule { generated_name, .. } => ("global", name, defined(generated_name)),
+                Global::Imported { name: symbol, .. } => ("global", name, symbol.clone()),
+            },
+            Export::Memory { .. } => continue,
+        };
+        symbol_map.exports.push(MappedExport {
+            kind,
+            name: name.clone(),
+            symbol,
+        });
+    }
+
+    Ok(symbol_map)
+}
+
+pub fn write_symbol_map(symbol_map: &SymbolMap, path: &std::path::Path) -> io::Result<()> {
+    let text = toml::to_string(symbol_map).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AWKWARD_NAMES: &[&str] = &[
+        "",
+        "plain",
+        "_",
+        "__",
+        "_s",
+        "a_s_b",
+        "_x41",
+        "wasi_snapshot_preview1",
+        "with space",
+        "dotted.name",
+        "ünïcödé",
+        "trailing_",
+    ];
+
+    #[test]
+    fn escaping_round_trips() {
+        for &name in AWKWARD_NAMES {
+            let escaped = escape(name);
+            assert!(escaped.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_'), "{}", escaped);
+            assert_eq!(unescape(&escaped), Some((name.to_string(), None)));
+        }
+    }
+
+    #[test]
+    fn unescaping_rejects_malformed_input() {
+        assert_eq!(unescape("a_"), None);
+        assert_eq!(unescape("a_q"), None);
+        assert_eq!(unescape("_x4"), None);
+        assert_eq!(unescape("_xzz"), None);
+        // A lone continuation byte is not UTF-8
+        assert_eq!(unescape("_x80"), None);
+    }
+
+    #[test]
+    fn unescaping_rejects_non_canonical_input() {
+        assert_eq!(unescape("_x0a"), Some(("\n".to_string(), None)));
+        assert_eq!(unescape("_x0A"), None);
+        assert_eq!(unescape("_x+a"), None);
+        assert_eq!(unescape("_x-1"), None);
+        assert_eq!(unescape("_x 1"), None);
+        // Letters, digits and `_` are never written as `_xHH`
+        assert_eq!(unescape("_x41"), None);
+        assert_eq!(unescape("_x5f"), None);
+    }
+
+    #[test]
+    fn imports_round_trip() {
+        for &module in AWKWARD_NAMES {
+            for &field in AWKWARD_NAMES {
+                let symbol = mangle_import(module, field);
+                assert_eq!(demangle_import(&symbol), Some((module.to_string(), field.to_string())));
+            }
+        }
+        assert_eq!(demangle_import("wasm_import_env"), None);
+        assert_eq!(demangle_import("wasm_import_a_sb_sc"), None);
+        assert_eq!(demangle_import("env_f"), None);
+    }
+
+    #[test]
+    fn exports_round_trip() {
+        for &name in AWKWARD_NAMES {
+            assert_eq!(demangle_export(&mangle_export(name)), Some(name.to_string()));
+        }
+        assert_eq!(demangle_export("wasm_export_a_sb"), None);
+        assert_eq!(demangle_export("main"), None);
+    }
+
+    #[test]
+    fn mangled_symbols_are_distinct() {
+        let mut seen = HashMap::new();
+        for &module in AWKWARD_NAMES {
+            for &field in AWKWARD_NAMES {
+                let symbol = mangle_import(module, field);
+                assert_eq!(seen.insert(symbol, (module, field)), None);
+            }
+        }
+    }
+
+    fn runtime_owners(strict_runtime: bool) -> SymbolOwners {
+        let mut owners = SymbolOwners { owners: HashMap::new(), strict_runtime };
+        owners.claim("puts".to_string(), "runtime symbol puts".to_string(), Claim::Runtime).unwrap();
+        owners
+    }
+
+    fn log_import() -> Claim {
+        Claim::Function(vec![AbiType::I32], None)
+    }
+
+    #[test]
+    fn imports_may_share_a_host_function() {
+        let mut owners = runtime_owners(true);
+        owners.claim("log".to_string(), "import env.log".to_string(), log_import()).unwrap();
+        owners.claim("log".to_string(), "import env.print".to_string(), log_import()).unwrap();
+        let different = Claim::Function(vec![AbiType::I64], None);
+        assert!(owners.claim("log".to_string(), "import env.wide".to_string(), different).is_err());
+    }
+
+    #[test]
+    fn imports_may_be_bound_to_the_runtime() {
+        let mut owners = runtime_owners(true);
+        owners.claim("puts".to_string(), "import env.log".to_string(), log_import()).unwrap();
+        owners.claim("puts".to_string(), "import env.print".to_string(), log_import()).unwrap();
+    }
+
+    #[test]
+    fn definitions_never_share() {
+        let mut owners = runtime_owners(true);
+        owners.claim("f".to_string(), "function 0".to_string(), Claim::Definition).unwrap();
+        assert!(owners.claim("f".to_string(), "function 1".to_string(), Claim::Definition).is_err());
+        assert!(owners.claim("f".to_string(), "import env.f".to_string(), log_import()).is_err());
+        assert!(owners.claim("puts".to_string(), "function 2".to_string(), Claim::Definition).is_err());
+
+        let mut lenient = runtime_owners(false);
+        lenient.claim("puts".to_string(), "function 2".to_string(), Claim::Definition).unwrap();
+    }
+}
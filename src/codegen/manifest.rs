@@ -0,0 +1,27 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::codegen::abi::ModuleAbi;
+
+// Bumped whenever a field changes meaning, so build tooling can refuse manifests it does not understand
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    manifest_version: u32,
+    #[serde(flatten)]
+    module: &'a ModuleAbi,
+}
+
+// Writes the module's ABI as JSON, so build systems can see what an object contains without parsing the wasm
+pub fn write_manifest(abi: &ModuleAbi, path: &Path) -> io::Result<()> {
+    let manifest = Manifest {
+        manifest_version: MANIFEST_VERSION,
+        module: abi,
+    };
+    let text = serde_json::to_string_pretty(&manifest).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    fs::write(path, text + "\n")
+}
@@ -1,7 +1,12 @@
+use std::ffi::CString;
+use std::io;
+
+use llvm::ffi::LLVMLinkage;
 use llvm::{Builder, Context, GlobalValue, GlobalVariable};
 use llvm::Compile;
 use llvm::FunctionType;
 use llvm::PointerType;
+use llvm::Predicate;
 use llvm::Sub;
 use llvm::Value;
 use llvm::Module as LLVMModule;
@@ -14,7 +19,23 @@ use crate::wasm::Instruction;
 
 use crate::codegen::ModuleCtx;
 use crate::codegen::function::compile_function;
+use crate::codegen::runtime_stubs::get_stub_function;
+use crate::codegen::runtime_stubs::MEMCPY;
+use crate::codegen::runtime_stubs::TRAP;
 use crate::codegen::type_conversions::wasm_func_type_to_llvm_type;
+use crate::codegen::vmctx::MEMORY_BASE_FIELD;
+use crate::codegen::vmctx::MEMORY_SIZE_FIELD;
+
+// Let the host find linear memory without knowing how (or where) we store it
+pub const MEMORY_ACCESSOR: &str = "wasm_memory";
+pub const MEMORY_SIZE_ACCESSOR: &str = "wasm_memory_size";
+// The zeroed end of a memory image placed in a section, and the check that it landed right after the image
+pub const MEMORY_IMAGE_TAIL: &str = "linear_memory_tail";
+pub const MEMORY_IMAGE_CHECK: &str = "wasm_memory_image_contiguous";
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+// Enough for any wasm access to be naturally aligned, and for linker scripts placing the image in its own region
+const WASM_IMAGE_ALIGNMENT: u32 = 16;
 
 // We add in globals to tell the runtime how much memory to allocate and startup
 // (And what the max amount of allocated memory should be)
@@ -38,6 +59,250 @@ pub fn generate_linear_memory_simulation<'a>(ctx: &'a Context, module: &'a LLVMM
     (*module).add_global_variable(&"linear_memory", Value::new_vector(&data_vec))
 }
 
+pub struct MemoryImage<'a> {
+    pub memory: &'a GlobalVariable,
+    // The zeroed rest of memory, when it was split off into its own section
+    pub tail: Option<&'a GlobalVariable>,
+    // Segments from this one on are left to populate_memory, see generate_image_population
+    pub first_runtime_segment: usize,
+}
+
+// Linear memory with its data segments already in place, so they do not have to be copied in at startup
+// Only the bytes up to the end of the last segment are spelled out, the rest of the initial pages is zeroes
+// With a section those zeroes are split off into their own `.bss.<section>` global, so they take no space in the image,
+// and the linker script has to place it directly after the image. Without one both halves share a single global in .data
+// Segments are folded up to the first one with an offset only known at runtime, since a later one may overlap it
+pub fn generate_linear_memory_image<'a>(
+    ctx: &'a Context,
+    module: &'a LLVMModule,
+    limits: &ResizableLimits,
+    initializers: &[DataInitializer],
+    section: Option<&str>,
+) -> io::Result<MemoryImage<'a>> {
+    let memory_size = limits.initial as u64 * WASM_PAGE_SIZE;
+    if memory_size > u32::max_value() as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} pages of initial memory do not fit in a memory image", limits.initial),
+        ));
+    }
+
+    // Segments are applied in order, so a later one overwrites an earlier one where they overlap
+    let mut image: Vec<u8> = Vec::new();
+    let mut first_runtime_segment = initializers.len();
+    for (n, i) in initializers.iter().enumerate() {
+        let offset = match evaluate_constant_offset(&i.offset_expression) {
+            Some(offset) => offset as u64,
+            None => {
+                first_runtime_segment = n;
+                break;
+            }
+        };
+        let length: u64 = i.body.iter().map(|d| d.len() as u64).sum();
+        if offset + length > memory_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("data segment {} ends at {}, past the {} bytes of initial memory", n, offset + length, memory_size),
+            ));
+        }
+
+        let end = (offset + length) as usize;
+        if image.len() < end {
+            image.resize(end, 0);
+        }
+        let mut position = offset as usize;
+        for d in &i.body {
+            image[position..position + d.len()].copy_from_slice(d);
+            position += d.len();
+        }
+    }
+    let tail_size = (memory_size - image.len() as u64) as u32;
+
+    let (data, zeroes): (&Value, &Value) = unsafe {
+        use llvm::ffi::core;
+        let i8_type = <i8>::get_type(ctx).into();
+        (
+            core::LLVMConstStringInContext(ctx.into(), image.as_ptr() as *const _, image.len() as u32, 1).into(),
+            core::LLVMConstNull(core::LLVMArrayType(i8_type, tail_size)).into(),
+        )
+    };
+
+    let section = match section {
+        Some(section) if tail_size > 0 => section,
+        _ => {
+            let image_value: &Value = unsafe {
+                let mut fields = [data.into(), zeroes.into()];
+                llvm::ffi::core::LLVMConstStructInContext(ctx.into(), fields.as_mut_ptr(), fields.len() as u32, 1).into()
+            };
+            let linear_memory = module.add_global_variable("linear_memory", image_value);
+            place_global(linear_memory, WASM_IMAGE_ALIGNMENT, section);
+            return Ok(MemoryImage {
+                memory: linear_memory,
+                tail: None,
+                first_runtime_segment,
+            });
+        }
+    };
+
+    let linear_memory = module.add_global_variable("linear_memory", data);
+    place_global(linear_memory, WASM_IMAGE_ALIGNMENT, Some(section));
+    // Byte aligned, so nothing on our side pads it away from the end of the image
+    let tail = module.add_global_variable(MEMORY_IMAGE_TAIL, zeroes);
+    let tail_section = format!(".bss.{}", section.trim_start_matches('.'));
+    place_global(tail, 1, Some(&tail_section));
+
+    generate_image_contiguity_check(ctx, module, linear_memory, image.len() as u32, tail);
+    Ok(MemoryImage {
+        memory: linear_memory,
+        tail: Some(tail),
+        first_runtime_segment,
+    })
+}
+
+// populate_memory for a memory image, copying in the segments it could not fold in order
+// Like instantiation, a segment that does not fit in initial memory traps
+pub fn generate_image_population(
+    ctx: &ModuleCtx,
+    limits: &ResizableLimits,
+    initializers: &[DataInitializer],
+    first: usize,
+) {
+    let llvm_ctx = ctx.llvm_ctx;
+    let memory_size = limits.initial as u64 * WASM_PAGE_SIZE;
+    let u8_ptr = PointerType::new(<u8>::get_type(llvm_ctx));
+
+    let setup_function = ctx.llvm_module.add_function(
+        "populate_memory",
+        FunctionType::new(<()>::get_type(llvm_ctx), &[]).to_super(),
+    );
+    let b = Builder::new(llvm_ctx);
+    b.position_at_end(setup_function.append("entry"));
+    let memory_base = b.build_bit_cast(ctx.linear_memory.unwrap().to_super(), u8_ptr);
+    let not_volatile: &Value = unsafe {
+        use llvm::ffi::core;
+        core::LLVMConstInt(core::LLVMInt1TypeInContext(llvm_ctx.into()), 0, 0).into()
+    };
+
+    for (n, i) in initializers.iter().enumerate().skip(first) {
+        let offset_func = generate_offset_function(ctx, "memory", n, i.offset_expression.clone());
+        let data: Vec<u8> = i.body.concat();
+        let data_global: &Value = unsafe {
+            use llvm::ffi::core;
+            let c_name = CString::new(format!("init_vector_{}", n)).unwrap();
+            let value = core::LLVMConstStringInContext(llvm_ctx.into(), data.as_ptr() as *const _, data.len() as u32, 1);
+            let g = core::LLVMAddGlobal(ctx.llvm_module.into(), core::LLVMTypeOf(value), c_name.as_ptr());
+            core::LLVMSetInitializer(g, value);
+            core::LLVMSetGlobalConstant(g, 1);
+            core::LLVMSetLinkage(g, LLVMLinkage::LLVMPrivateLinkage);
+            g.into()
+        };
+
+        // Done in 64 bits, so offset + length cannot wrap
+        let offset = b.build_zext(b.build_call(offset_func, &[]), <u64>::get_type(llvm_ctx));
+        let length = (data.len() as u64).compile(llvm_ctx);
+        let end = b.build_add(offset, length);
+        let out_of_bounds = b.build_unsigned_cmp(end, memory_size.compile(llvm_ctx), Predicate::GreaterThan);
+
+        let trap_bb = setup_function.append(&format!("segment_{}_out_of_bounds", n));
+        let copy_bb = setup_function.append(&format!("segment_{}", n));
+        b.build_cond_br(out_of_bounds, trap_bb, Some(copy_bb));
+        b.position_at_end(trap_bb);
+        b.build_call(get_stub_function(ctx, TRAP), &[]);
+        b.build_unreachable();
+
+        b.position_at_end(copy_bb);
+        b.build_call(
+            get_stub_function(ctx, MEMCPY),
+            &[b.build_gep(memory_base, &[offset]), b.build_bit_cast(data_global, u8_ptr), length, not_volatile],
+        );
+    }
+    b.build_ret_void();
+}
+
+fn place_global(g: &GlobalVariable, alignment: u32, section: Option<&str>) {
+    unsafe {
+        use llvm::ffi::core;
+        let global: &Value = g.to_super();
+        core::LLVMSetAlignment(global.into(), alignment);
+        if let Some(section) = section {
+            let c_section = CString::new(section).unwrap();
+            core::LLVMSetSection(global.into(), c_section.as_ptr());
+        }
+    }
+}
+
+// Nothing but the linker script keeps the tail right after the image, so the runtime asks before trusting it
+fn generate_image_contiguity_check(
+    ctx: &Context,
+    module: &LLVMModule,
+    linear_memory: &GlobalVariable,
+    image_size: u32,
+    tail: &GlobalVariable,
+) {
+    let check_function = module.add_function(
+        MEMORY_IMAGE_CHECK,
+        FunctionType::new(<i32>::get_type(ctx), &[]).to_super(),
+    );
+    let b = Builder::new(ctx);
+    b.position_at_end(check_function.append("entry"));
+
+    let u8_ptr = PointerType::new(<u8>::get_type(ctx));
+    let image_end = b.build_gep(b.build_bit_cast(linear_memory.to_super(), u8_ptr), &[image_size.compile(ctx)]);
+    let tail_start = b.build_bit_cast(tail.to_super(), u8_ptr);
+    let contiguous = b.build_unsigned_cmp(image_end, tail_start, Predicate::Equal);
+    b.build_ret(b.build_zext(contiguous, <i32>::get_type(ctx)));
+}
+
+// `u8* wasm_memory()` and `u32 wasm_memory_size()`, both taking the instance with --vmctx
+pub fn generate_memory_accessors(ctx: &ModuleCtx) {
+    let llvm_ctx = ctx.llvm_ctx;
+    let params: Vec<&llvm::Type> = ctx.instance_type.map(PointerType::new).into_iter().collect();
+
+    let base_function = ctx.llvm_module.add_function(
+        MEMORY_ACCESSOR,
+        FunctionType::new(PointerType::new(<u8>::get_type(llvm_ctx)), &params).to_super(),
+    );
+    let size_function = ctx.llvm_module.add_function(
+        MEMORY_SIZE_ACCESSOR,
+        FunctionType::new(<u32>::get_type(llvm_ctx), &params).to_super(),
+    );
+
+    let b = Builder::new(llvm_ctx);
+    match (ctx.instance_type, ctx.linear_memory) {
+        (Some(_), _) => {
+            let field = |f: &llvm::Function, field: u32| {
+                let instance: &Value = &*f[0];
+                b.build_load(b.build_gep(instance, &[0u32.compile(llvm_ctx), field.compile(llvm_ctx)]))
+            };
+            b.position_at_end(base_function.append("entry"));
+            b.build_ret(field(base_function, MEMORY_BASE_FIELD));
+            b.position_at_end(size_function.append("entry"));
+            b.build_ret(field(size_function, MEMORY_SIZE_FIELD));
+        }
+        (None, Some(linear_memory)) => {
+            b.position_at_end(base_function.append("entry"));
+            b.build_ret(b.build_bit_cast(linear_memory.to_super(), PointerType::new(<u8>::get_type(llvm_ctx))));
+
+            // A memory image split into a section keeps the zeroed rest of memory in its tail
+            let size: &Value = unsafe {
+                use llvm::ffi::core;
+                let size_of = |g: &GlobalVariable| {
+                    let g_value: &Value = g.to_super();
+                    core::LLVMSizeOf(core::LLVMGetElementType(core::LLVMTypeOf(g_value.into())))
+                };
+                let mut memory_size = size_of(linear_memory);
+                if let Some(tail) = ctx.linear_memory_tail {
+                    memory_size = core::LLVMConstAdd(memory_size, size_of(tail));
+                }
+                core::LLVMConstTruncOrBitCast(memory_size, <u32>::get_type(llvm_ctx).into()).into()
+            };
+            b.position_at_end(size_function.append("entry"));
+            b.build_ret(size);
+        }
+        (None, None) => panic!("memory accessors need linear memory"),
+    }
+}
+
 pub fn generate_memory_initialization_stub(ctx: &ModuleCtx, initializers: Vec<DataInitializer>) {
     let mut initialization_data: Vec<(&llvm::Function, Vec<u8>)> = Vec::new();
 
@@ -89,6 +354,14 @@ pub fn generate_memory_initialization_stub(ctx: &ModuleCtx, initializers: Vec<Da
     b.build_ret_void();
 }
 
+// Segment offsets are almost always a single constant, which lets us skip the offset function entirely
+pub fn evaluate_constant_offset(offset_expression: &[Instruction]) -> Option<u32> {
+    match offset_expression {
+        [Instruction::I32Const(offset)] => Some(*offset as u32),
+        _ => None,
+    }
+}
+
 pub fn generate_offset_function<'a>(
     ctx: &'a ModuleCtx,
     prefix: &str,
@@ -123,3 +396,91 @@ pub fn generate_offset_function<'a>(
 
     offset_func
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CStr;
+
+    fn limits(initial: u32) -> ResizableLimits {
+        ResizableLimits { initial, maximum: None }
+    }
+
+    fn segment(offset: i32, body: &[u8]) -> DataInitializer {
+        DataInitializer { offset_expression: vec![Instruction::I32Const(offset)], body: vec![body.to_vec()] }
+    }
+
+    fn array_length(g: &GlobalVariable) -> u32 {
+        let g_value: &Value = g.to_super();
+        unsafe {
+            use llvm::ffi::core;
+            core::LLVMGetArrayLength(core::LLVMGetElementType(core::LLVMTypeOf(g_value.into())))
+        }
+    }
+
+    fn section(g: &GlobalVariable) -> String {
+        let g_value: &Value = g.to_super();
+        unsafe { CStr::from_ptr(llvm::ffi::core::LLVMGetSection(g_value.into())).to_string_lossy().into_owned() }
+    }
+
+    #[test]
+    fn memory_image_splits_off_the_zeroed_tail_in_a_section() {
+        let ctx = Context::new();
+        let module = LLVMModule::new("image", &ctx);
+        let segments = [segment(16, &[1, 2, 3, 4])];
+        let image = generate_linear_memory_image(&ctx, &module, &limits(1), &segments, Some(".wasm_image")).unwrap();
+        let tail = image.tail.unwrap();
+
+        assert_eq!(array_length(image.memory), 20);
+        assert_eq!(section(image.memory), ".wasm_image");
+        assert_eq!(array_length(tail) as u64, WASM_PAGE_SIZE - 20);
+        assert_eq!(section(tail), ".bss.wasm_image");
+        assert!(module.get_function(MEMORY_IMAGE_CHECK).is_some());
+    }
+
+    #[test]
+    fn memory_image_without_a_section_is_one_global() {
+        let ctx = Context::new();
+        let module = LLVMModule::new("image", &ctx);
+        let segments = [segment(16, &[1, 2, 3, 4])];
+        let image = generate_linear_memory_image(&ctx, &module, &limits(1), &segments, None).unwrap();
+
+        assert!(image.tail.is_none());
+        assert!(module.get_function(MEMORY_IMAGE_CHECK).is_none());
+    }
+
+    #[test]
+    fn memory_image_rejects_four_gigabytes() {
+        let ctx = Context::new();
+        let module = LLVMModule::new("image", &ctx);
+        assert!(generate_linear_memory_image(&ctx, &module, &limits(65536), &[], None).is_err());
+        assert!(generate_linear_memory_image(&ctx, &module, &limits(65535), &[], None).is_ok());
+    }
+
+    #[test]
+    fn memory_image_rejects_segments_past_initial_memory() {
+        let ctx = Context::new();
+        let module = LLVMModule::new("image", &ctx);
+        let segments = [segment(WASM_PAGE_SIZE as i32 - 2, &[1, 2, 3])];
+        assert!(generate_linear_memory_image(&ctx, &module, &limits(1), &segments, None).is_err());
+    }
+
+    #[test]
+    fn memory_image_folds_segments_up_to_the_first_runtime_offset() {
+        let ctx = Context::new();
+        let module = LLVMModule::new("image", &ctx);
+        let runtime_offset = DataInitializer {
+            offset_expression: vec![Instruction::GetGlobal { index: 0 }],
+            body: vec![vec![5]],
+        };
+        let segments = [segment(0, &[1, 2]), segment(8, &[3, 4]), runtime_offset, segment(16, &[6])];
+        let image = generate_linear_memory_image(&ctx, &module, &limits(1), &segments, Some(".wasm_image")).unwrap();
+
+        assert_eq!(image.first_runtime_segment, 2);
+        assert_eq!(array_length(image.memory), 10);
+
+        let image = generate_linear_memory_image(&ctx, &module, &limits(1), &segments[..2], None).unwrap();
+        assert_eq!(image.first_runtime_segment, 2);
+    }
+}
@@ -4,49 +4,150 @@ use llvm::{Context as LLVMCtx, GlobalVariable};
 use llvm::Function as LLVMFunction;
 use llvm::Module as LLVMModule;
 
+use memory::generate_linear_memory_image;
 use memory::generate_linear_memory_simulation;
 use wasmparser::FuncType;
 
 use crate::Opt;
 
-use crate::wasm::Export;
 use crate::wasm::Function;
 use crate::wasm::WasmModule;
 
+mod abi;
+use self::abi::describe_module;
+
 mod block;
 
 mod breakout;
 
+mod fuel;
+use self::fuel::insert_fuel_global;
+
+mod emit;
+pub use self::emit::EmitKind;
+use self::emit::emit_module;
+use self::emit::target_data_layout;
+
+mod link;
+use self::link::link_runtime;
+
+mod optimize;
+pub use self::optimize::OptLevel;
+use self::optimize::optimize_module;
+use self::optimize::verify_module;
+
+mod devirtualize;
+use self::devirtualize::analyze_static_table;
+
 mod function;
 use self::function::compile_function;
 
 mod globals;
 use self::globals::insert_globals;
+use self::globals::insert_heap_base_index;
 use self::globals::GlobalValue;
 
+mod header;
+use self::header::write_header;
+
+mod imports;
+use self::imports::bind_imports;
+use self::imports::generate_import_thunks;
+use self::imports::load_import_manifest;
+
+mod interrupt;
+use self::interrupt::insert_interrupt_globals;
+
+mod mangle;
+use self::mangle::assign_symbols;
+use self::mangle::build_symbol_map;
+use self::mangle::write_symbol_map;
+
+mod manifest;
+use self::manifest::write_manifest;
+
+mod lowering;
+
 mod memory;
 //use self::memory::add_memory_size_globals;
+use self::memory::generate_image_population;
+use self::memory::generate_memory_accessors;
 use self::memory::generate_memory_initialization_stub;
 
+mod rust_bindings;
+use self::rust_bindings::write_rust_bindings;
+
+mod profiles;
+pub use self::profiles::AbiProfile;
+use self::profiles::bind_profile;
+
 mod runtime_stubs;
 use self::runtime_stubs::insert_runtime_stubs;
 
+mod stack_guard;
+use self::stack_guard::insert_stack_guard_globals;
+
 mod table;
+use self::table::generate_inline_table;
 use self::table::generate_table_initialization_stub;
 
+mod symbols;
+use self::symbols::prefix_definitions;
+use self::symbols::prefix_imports;
+
+mod target;
+pub use self::target::Preset;
+use self::target::resolve_target;
+use self::target::TargetSettings;
+
+mod vmctx;
+use self::vmctx::build_instance_type;
+use self::vmctx::generate_instance_api;
+
+mod visibility;
+use self::visibility::analyze_visibility;
+use self::visibility::delete_function;
+use self::visibility::set_internal;
+
+mod wasi;
+use self::wasi::bind_wasi;
+
+mod thread_local;
+use self::thread_local::generate_thread_entry_points;
+use self::thread_local::mark_thread_local;
+
 mod type_conversions;
-use self::type_conversions::wasm_func_type_to_llvm_type;
+use self::type_conversions::wasm_func_type_to_instance_llvm_type;
 
 pub struct ModuleCtx<'a> {
     opt: &'a Opt,
+    target: &'a TargetSettings,
     llvm_ctx: &'a LLVMCtx,
     linear_memory: Option<&'a GlobalVariable>,
+    linear_memory_tail: Option<&'a GlobalVariable>,
+    call_depth: Option<&'a GlobalVariable>,
+    stack_limit: Option<&'a GlobalVariable>,
+    fuel: Option<&'a GlobalVariable>,
+    interrupt_epoch: Option<&'a GlobalVariable>,
+    epoch_deadline: Option<&'a GlobalVariable>,
+    table: Option<&'a GlobalVariable>,
+    table_size: u32,
+    static_table: Option<&'a [Option<u32>]>,
     llvm_module: &'a LLVMModule,
+    instance_type: Option<&'a llvm::Type>,
     types: &'a [FuncType],
+    canonical_types: &'a [u32],
     globals: &'a [GlobalValue<'a>],
     functions: &'a [(&'a LLVMFunction, Function)],
 }
 
+impl<'a> ModuleCtx<'a> {
+    // The id that a function of this type is registered with in the table, and checked against when called
+    pub fn canonical_type_id(&self, type_index: u32) -> u32 {
+        self.canonical_types[type_index as usize]
+    }
+}
+
 pub fn process_to_llvm(
     opt: &Opt,
     mut wasm_module: WasmModule,
@@ -55,31 +156,71 @@ pub fn process_to_llvm(
     let llvm_ctx = &*LLVMCtx::new();
     let llvm_module = &*LLVMModule::new(&wasm_module.source_name, llvm_ctx);
 
+    // Combine --preset with the individual target flags
+    let mut target = resolve_target(opt);
+    // Any named target gets its layout from LLVM itself unless --layout overrides it, before any IR depends on it
+    if target.layout.is_none() && target.triple.is_some() {
+        target.layout = Some(target_data_layout(opt, &target)?);
+    }
+    let target = &target;
+
     // Accept --target to compile for specific target, otherwise omit target
     // triple from bytecode, this defaults to the host target in LLVM
-    if let Some(ref target) = opt.target {
-        llvm_module.set_target(target);
+    if let Some(ref triple) = target.triple {
+        llvm_module.set_target(triple);
     }
     // Accept --layout to compile for specific target, otherwise omit target
     // data layout string, this defaults to the host target in LLVM
-    if let Some(ref layout) = opt.layout {
+    if let Some(ref layout) = target.layout {
         unsafe {
             let c_target = std::ffi::CString::new(layout.to_string()).unwrap();
             llvm::ffi::core::LLVMSetDataLayout(llvm_module.into(), c_target.as_ptr());
         }
     }
-    // Remap WASM generated names to exported names
-    for e in wasm_module.exports {
-        match e {
-            Export::Function { index, name } => {
-                wasm_module.functions[index].set_name(name);
-            }
-            Export::Global { index, name } => {
-                wasm_module.globals[index].set_name(name);
-            }
-            // Exporting memory is meaningless in our native embedding
-            Export::Memory { .. } => {}
-        }
+    // Work out what is reachable before export names replace the generated ones
+    let visibility = analyze_visibility(opt, &wasm_module)?;
+
+    // Remap WASM generated names to import and export symbols
+    assign_symbols(opt, &mut wasm_module);
+
+    if let Some(ref prefix) = opt.import_prefix {
+        prefix_imports(prefix, &mut wasm_module);
+    }
+
+    // Symbols from the manifest are taken as written, so this comes after prefixing
+    // Each pass keeps what the ones before it bound, and the manifest has the final say
+    let mut resolved_imports = None;
+    let mut lower_wasi = opt.wasi;
+    if let Some(profile) = opt.abi_profile {
+        let (profile, resolved) = bind_profile(profile, &mut wasm_module)?;
+        info!("Using the {:?} abi profile", profile);
+        lower_wasi |= profile.lowers_wasi();
+        resolved_imports = Some(resolved);
+    }
+    if lower_wasi {
+        resolved_imports = Some(bind_wasi(&mut wasm_module, resolved_imports)?);
+    }
+    if let Some(ref manifest_path) = opt.imports {
+        let manifest = load_import_manifest(manifest_path)?;
+        resolved_imports = Some(bind_imports(&manifest, &mut wasm_module, resolved_imports)?);
+    }
+
+    // Every symbol is final at this point, so collisions can be caught before LLVM renames anything
+    let symbol_map = build_symbol_map(opt, &wasm_module, resolved_imports.as_deref())?;
+    if let Some(ref symbol_map_path) = opt.symbol_map {
+        write_symbol_map(&symbol_map, symbol_map_path)?;
+    }
+
+    // Descriptions of the ABI for the host, derived from the same decisions codegen makes below
+    let abi = describe_module(opt, &wasm_module, &visibility, resolved_imports.as_deref());
+    if let Some(ref header_path) = opt.emit_header {
+        write_header(&abi, header_path)?;
+    }
+    if let Some(ref bindings_path) = opt.emit_rust_bindings {
+        write_rust_bindings(&abi, bindings_path)?;
+    }
+    if let Some(ref manifest_path) = opt.emit_manifest {
+        write_manifest(&abi, manifest_path)?;
     }
 
     info!("Inserting runtime stubs...");
@@ -88,31 +229,72 @@ pub fn process_to_llvm(
 
     info!("Inserting globals...");
     // Wasm globals have a natural mapping to llvm globals
-    let globals = insert_globals(&opt, llvm_ctx, llvm_module, wasm_module.globals);
+    let globals = insert_globals(&opt, llvm_ctx, llvm_module, wasm_module.globals)?;
+    if opt.use_runtime_global_handling {
+        insert_heap_base_index(llvm_ctx, llvm_module, &wasm_module.exports);
+    }
+
+    // The instance struct holds the globals, so it can only be laid out once they are known
+    let instance_type = if opt.vmctx {
+        Some(build_instance_type(llvm_ctx, llvm_module, &globals))
+    } else {
+        None
+    };
+
+    info!("Inserting stack guard globals...");
+    // These are shared with the runtime, which resets them after a trap
+    let (call_depth, stack_limit) = insert_stack_guard_globals(opt, llvm_ctx, llvm_module);
+
+    info!("Inserting fuel counter...");
+    let fuel = insert_fuel_global(opt, llvm_ctx, llvm_module);
+
+    info!("Inserting interrupt epoch...");
+    let (interrupt_epoch, epoch_deadline) = insert_interrupt_globals(opt, llvm_ctx, llvm_module);
 
 
     info!("Prototyping functions...");
     // We need to prototype functions before implementing any, in case a function calls a function implemented after it
     let mut functions = Vec::new();
-    for f in &wasm_module.functions {
+    for (n, f) in wasm_module.functions.iter().enumerate() {
 
         info!("Adding function {}...", f.get_name().clone());
         let llvm_f = llvm_module.add_function(
             f.get_name(),
-            wasm_func_type_to_llvm_type(&llvm_ctx, f.get_type()),
+            wasm_func_type_to_instance_llvm_type(&llvm_ctx, f.get_type(), instance_type),
         );
+        // Imports have to stay external, they are defined elsewhere
+        if visibility.live[n] && !visibility.public[n] {
+            if let Function::Implemented { .. } = f {
+                set_internal(llvm_f);
+            }
+        }
         functions.push((&*llvm_f, f.clone()));
 
         info!("Done {}", f.get_name().clone());
     }
 
+    // If the table contents are fixed at compile time, indirect calls can often become direct ones
+    let static_table = analyze_static_table(&wasm_module);
+
     // The global information about a module makes up the module context
     let mut module_ctx = ModuleCtx {
         opt,
+        target,
         llvm_ctx,
         llvm_module,
+        instance_type,
         linear_memory: None,
+        linear_memory_tail: None,
+        call_depth,
+        stack_limit,
+        fuel,
+        interrupt_epoch,
+        epoch_deadline,
+        table: None,
+        table_size: 0,
+        static_table: static_table.as_ref().map(|t| t.as_slice()),
         types: wasm_module.types.as_slice(),
+        canonical_types: wasm_module.canonical_types.as_slice(),
         functions: functions.as_slice(),
         globals: globals.as_slice(),
     };
@@ -128,32 +310,130 @@ pub fn process_to_llvm(
     info!("Checking mem ");
 
     // Which we then need to initialize the data
-    if wasm_module.memories.len() >= 1 {
-        info!("Generating mem init...");
-        let linear_mem: &GlobalVariable = generate_linear_memory_simulation(llvm_ctx, llvm_module);
+    // With --vmctx the host hands every instance its own memory instead
+    if wasm_module.memories.len() >= 1 && !opt.vmctx {
+        let (linear_mem, linear_mem_tail, first_runtime_segment) = if opt.memory_image {
+            info!("Generating memory image...");
+            let image = generate_linear_memory_image(
+                llvm_ctx,
+                llvm_module,
+                &wasm_module.memories[0].limits,
+                &wasm_module.data_initializers,
+                opt.memory_image_section.as_deref(),
+            )?;
+            (image.memory, image.tail, Some(image.first_runtime_segment))
+        } else {
+            info!("Generating mem init...");
+            (generate_linear_memory_simulation(llvm_ctx, llvm_module), None, None)
+        };
+        mark_thread_local(opt, linear_mem);
+        if let Some(tail) = linear_mem_tail {
+            mark_thread_local(opt, tail);
+        }
+        module_ctx.linear_memory_tail = linear_mem_tail;
         module_ctx.linear_memory = Some(linear_mem);
+        // Segments with offsets only known at runtime still have to be copied in
+        if let Some(first) = first_runtime_segment {
+            generate_image_population(
+                &module_ctx,
+                &wasm_module.memories[0].limits,
+                &wasm_module.data_initializers,
+                first,
+            );
+        }
         //generate_memory_initialization_stub(&module_ctx, wasm_module.data_initializers);
     }    
+    if wasm_module.memories.len() >= 1 {
+        generate_memory_accessors(&module_ctx);
+    }
     // Assu me there is only one relevent table
     // CROW not necesary
     //assert_eq!(wasm_module.tables.len(), 1);
     // TODO: Do some sort of dynamic handling of table size
    
-    if wasm_module.tables.len() >= 1 {
+    // The instance points at the inline table, so --vmctx implies --inline-table
+    if wasm_module.tables.len() >= 1 && (opt.inline_table || opt.vmctx) {
+        info!("Generating inline table...");
+        // The inline table is sized by the module, so it has no limit beyond that
+        let table = generate_inline_table(
+            &module_ctx,
+            &wasm_module.tables[0],
+            wasm_module.table_initializers,
+        )?;
+        module_ctx.table = Some(table);
+        module_ctx.table_size = wasm_module.tables[0].limits.initial;
+    } else if wasm_module.tables.len() >= 1 {
         info!("Generating table init...");
         generate_table_initialization_stub(&module_ctx, wasm_module.table_initializers);
         assert!(wasm_module.tables[0].limits.initial <= 1024);
         assert!(wasm_module.tables[0].limits.maximum.unwrap_or(0) <= 1024);    
     }
+    if opt.vmctx {
+        info!("Generating instance api...");
+        generate_instance_api(&module_ctx);
+    }
+
+    // Runs after everything thread local has been created, since the entry points cover all of it
+    if opt.thread_local {
+        info!("Generating thread entry points...");
+        generate_thread_entry_points(&module_ctx);
+    }
+
+    // Import thunks read the memory base, so they come after linear memory is set up
+    if let Some(ref resolved_imports) = resolved_imports {
+        info!("Generating import thunks...");
+        generate_import_thunks(&module_ctx, resolved_imports);
+    }
+
     // Next we implement the implemented functions
-    for f in wasm_module.functions {
+    for (n, f) in wasm_module.functions.into_iter().enumerate() {
         if let Function::Implemented { f } = f {
-            compile_function(&module_ctx, &f);
+            if visibility.live[n] {
+                compile_function(&module_ctx, &f);
+            } else {
+                info!("Skipping unreachable function {}", f.generated_name);
+            }
         }
     }
 
     // TODO: Remove this debugging print
             //llvm_module.dump();
 
-    llvm_module.write_bitcode(output_path)
+    // Verify before optimizing, so any breakage is still in terms of the code we generated
+    if opt.verify {
+        info!("Verifying module...");
+        verify_module(&module_ctx)?;
+    }
+
+    // Nothing live refers to the dead functions, so their prototypes can be dropped
+    for (n, &(llvm_f, ref f)) in functions.iter().enumerate() {
+        if let Function::Implemented { .. } = f {
+            if !visibility.live[n] {
+                delete_function(llvm_f);
+            }
+        }
+    }
+
+    // Done last, so everything generated above is covered, but before the runtime brings in its own definitions
+    if let Some(ref prefix) = opt.symbol_prefix {
+        info!("Prefixing symbols with {}...", prefix);
+        prefix_definitions(llvm_module, prefix);
+    }
+
+    if let Some(ref runtime_path) = opt.link_runtime {
+        info!("Linking runtime {:?}...", runtime_path);
+        link_runtime(opt, llvm_module, runtime_path)?;
+    }
+
+    // Linking the runtime is pointless unless its accessors get inlined, so that implies optimization
+    let opt_level = match (opt.opt_level, &opt.link_runtime) {
+        (None, Some(_)) => Some(OptLevel::O2),
+        (level, _) => level,
+    };
+    if let Some(level) = opt_level {
+        info!("Optimizing module at {:?}...", level);
+        optimize_module(llvm_module, level);
+    }
+
+    emit_module(opt, target, llvm_module, output_path)
 }
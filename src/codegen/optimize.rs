@@ -0,0 +1,151 @@
+use std::ffi::CStr;
+use std::io;
+use std::ptr;
+use std::str::FromStr;
+
+use llvm::ffi::analysis::LLVMVerifierFailureAction;
+use llvm::ffi::analysis::LLVMVerifyFunction;
+use llvm::ffi::analysis::LLVMVerifyModule;
+use llvm::ffi::prelude::LLVMModuleRef;
+use llvm::ffi::target_machine::LLVMCodeGenOptLevel;
+use llvm::ffi::transforms::pass_manager_builder::*;
+use llvm::Module as LLVMModule;
+use llvm::Value;
+
+use crate::codegen::ModuleCtx;
+use crate::wasm::Function;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    Os,
+    Oz,
+}
+
+impl OptLevel {
+    // The (speed, size) levels, in the same sense as clang's -O flags
+    fn levels(self) -> (u32, u32) {
+        match self {
+            OptLevel::O0 => (0, 0),
+            OptLevel::O1 => (1, 0),
+            OptLevel::O2 => (2, 0),
+            OptLevel::O3 => (3, 0),
+            OptLevel::Os => (2, 1),
+            OptLevel::Oz => (2, 2),
+        }
+    }
+
+    pub fn codegen_level(self) -> LLVMCodeGenOptLevel {
+        match self {
+            OptLevel::O0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::O1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::O3 => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+            _ => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+        }
+    }
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Os),
+            "z" => Ok(OptLevel::Oz),
+            e => Err(format!("unknown optimization level -O{}, expected one of 0, 1, 2, 3, s, z", e)),
+        }
+    }
+}
+
+// Runs the LLVM verifier, blaming the wasm function that produced any broken IR
+pub fn verify_module(ctx: &ModuleCtx) -> io::Result<()> {
+    let mut broken = Vec::new();
+    for (n, &(llvm_f, ref wasm_f)) in ctx.functions.iter().enumerate() {
+        if let Function::Implemented { f } = wasm_f {
+            let f_value: &Value = llvm_f.to_super();
+            let failed = unsafe {
+                LLVMVerifyFunction(f_value.into(), LLVMVerifierFailureAction::LLVMReturnStatusAction)
+            };
+            if failed != 0 {
+                broken.push(format!("wasm function {} ({})", n, f.generated_name));
+            }
+        }
+    }
+
+    // The module verifier has the actual explanation, and also catches problems outside of functions
+    let module_ref: LLVMModuleRef = ctx.llvm_module.into();
+    let mut message = ptr::null_mut();
+    let failed = unsafe {
+        LLVMVerifyModule(
+            module_ref,
+            LLVMVerifierFailureAction::LLVMReturnStatusAction,
+            &mut message,
+        )
+    };
+    let details = unsafe {
+        let details = CStr::from_ptr(message).to_string_lossy().into_owned();
+        llvm::ffi::core::LLVMDisposeMessage(message);
+        details
+    };
+
+    if failed == 0 && broken.is_empty() {
+        return Ok(());
+    }
+    let culprits = if broken.is_empty() {
+        "generated module code".to_string()
+    } else {
+        broken.join(", ")
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("LLVM verification failed in {}:\n{}", culprits, details),
+    ))
+}
+
+pub fn optimize_module(llvm_module: &LLVMModule, level: OptLevel) {
+    let (speed, size) = level.levels();
+    let module_ref: LLVMModuleRef = llvm_module.into();
+
+    unsafe {
+        use llvm::ffi::core;
+
+        let builder = LLVMPassManagerBuilderCreate();
+        LLVMPassManagerBuilderSetOptLevel(builder, speed);
+        LLVMPassManagerBuilderSetSizeLevel(builder, size);
+        if speed > 1 {
+            // These are the thresholds clang uses for -O2/-Os/-Oz and -O3
+            let threshold = match (speed, size) {
+                (_, 2) => 25,
+                (_, 1) => 75,
+                (3, _) => 275,
+                _ => 225,
+            };
+            LLVMPassManagerBuilderUseInlinerWithThreshold(builder, threshold);
+        }
+
+        let function_passes = core::LLVMCreateFunctionPassManagerForModule(module_ref);
+        LLVMPassManagerBuilderPopulateFunctionPassManager(builder, function_passes);
+        let module_passes = core::LLVMCreatePassManager();
+        LLVMPassManagerBuilderPopulateModulePassManager(builder, module_passes);
+
+        core::LLVMInitializeFunctionPassManager(function_passes);
+        let mut f = core::LLVMGetFirstFunction(module_ref);
+        while !f.is_null() {
+            core::LLVMRunFunctionPassManager(function_passes, f);
+            f = core::LLVMGetNextFunction(f);
+        }
+        core::LLVMFinalizeFunctionPassManager(function_passes);
+        core::LLVMRunPassManager(module_passes, module_ref);
+
+        core::LLVMDisposePassManager(function_passes);
+        core::LLVMDisposePassManager(module_passes);
+        LLVMPassManagerBuilderDispose(builder);
+    }
+}
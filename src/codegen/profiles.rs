@@ -0,0 +1,322 @@
+use std::io;
+use std::str::FromStr;
+
+use wasmparser::FuncType;
+use wasmparser::Type;
+
+use crate::codegen::imports::ResolvedImport;
+use crate::codegen::lowering::LoweredCall;
+use crate::codegen::lowering::LoweredParam::*;
+use crate::wasm::Function;
+use crate::wasm::WasmModule;
+
+// The toolchain conventions a module's `env` imports follow
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AbiProfile {
+    // Work it out from the imports and the producers section
+    Auto,
+    Musl,
+    Emscripten,
+    AssemblyScript,
+}
+
+impl FromStr for AbiProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(AbiProfile::Auto),
+            "musl" => Ok(AbiProfile::Musl),
+            "emscripten" => Ok(AbiProfile::Emscripten),
+            "assemblyscript" => Ok(AbiProfile::AssemblyScript),
+            e => Err(format!(
+                "unknown abi profile {}, expected one of auto, musl, emscripten, assemblyscript",
+                e
+            )),
+        }
+    }
+}
+
+impl AbiProfile {
+    // Emscripten does its IO through WASI, so its modules need that lowered too
+    pub fn lowers_wasi(self) -> bool {
+        self == AbiProfile::Emscripten
+    }
+}
+
+enum ProfileBinding {
+    // Defined by runtime/libc under the default `env_<field>` symbol
+    Runtime,
+    Lowered(LoweredCall),
+    Inert,
+    Trap,
+}
+
+struct ProfileImport {
+    field: &'static str,
+    binding: ProfileBinding,
+}
+
+const fn runtime(field: &'static str) -> ProfileImport {
+    ProfileImport {
+        field,
+        binding: ProfileBinding::Runtime,
+    }
+}
+
+// What runtime/libc/libc_backing.c and env.c provide for wasm-musl
+const MUSL_IMPORTS: &[ProfileImport] = &[
+    runtime("__syscall"),
+    runtime("syscall_handler"),
+    runtime("__unmapself"),
+    runtime("a_and_64"),
+    runtime("a_cas"),
+    runtime("a_ctz_32"),
+    runtime("a_ctz_64"),
+    runtime("a_dec"),
+    runtime("a_fetch_add"),
+    runtime("a_inc"),
+    runtime("a_or"),
+    runtime("a_or_64"),
+    runtime("a_store"),
+    runtime("a_swap"),
+    runtime("do_barrier"),
+    runtime("do_crash"),
+    runtime("do_spin"),
+    runtime("getcycles"),
+    runtime("sin"),
+    runtime("cos"),
+];
+
+const EMSCRIPTEN_IMPORTS: &[ProfileImport] = &[
+    ProfileImport {
+        field: "emscripten_memcpy_big",
+        binding: ProfileBinding::Lowered(LoweredCall {
+            name: "emscripten_memcpy_big",
+            symbol: "emscripten_memcpy_big_lowered",
+            params: &[Offset, Offset, Scalar(Type::I32)],
+            result: Some(Type::I32),
+            fault_errno: None,
+        }),
+    },
+    // Linear memory never grows, so resizing always fails, which Emscripten's malloc copes with
+    ProfileImport {
+        field: "emscripten_resize_heap",
+        binding: ProfileBinding::Inert,
+    },
+    ProfileImport {
+        field: "emscripten_notify_memory_growth",
+        binding: ProfileBinding::Inert,
+    },
+    ProfileImport {
+        field: "abort",
+        binding: ProfileBinding::Trap,
+    },
+];
+
+const ASSEMBLYSCRIPT_IMPORTS: &[ProfileImport] = &[
+    ProfileImport {
+        field: "abort",
+        binding: ProfileBinding::Lowered(LoweredCall {
+            name: "abort",
+            symbol: "assemblyscript_abort",
+            params: &[Offset, Offset, Scalar(Type::I32), Scalar(Type::I32)],
+            result: None,
+            fault_errno: None,
+        }),
+    },
+    ProfileImport {
+        field: "trace",
+        binding: ProfileBinding::Inert,
+    },
+    ProfileImport {
+        field: "seed",
+        binding: ProfileBinding::Lowered(LoweredCall {
+            name: "seed",
+            symbol: "assemblyscript_seed",
+            params: &[],
+            result: Some(Type::F64),
+            fault_errno: None,
+        }),
+    },
+];
+
+fn profile_imports(profile: AbiProfile) -> &'static [ProfileImport] {
+    match profile {
+        AbiProfile::Auto => &[],
+        AbiProfile::Musl => MUSL_IMPORTS,
+        AbiProfile::Emscripten => EMSCRIPTEN_IMPORTS,
+        AbiProfile::AssemblyScript => ASSEMBLYSCRIPT_IMPORTS,
+    }
+}
+
+// The producers section is the most reliable sign, the telltale imports cover modules built without one
+fn detect_profile(wasm_module: &WasmModule) -> Option<AbiProfile> {
+    let produced_by = |tool: &str| wasm_module.producers.iter().any(|p| p.eq_ignore_ascii_case(tool));
+    if produced_by("Emscripten") {
+        return Some(AbiProfile::Emscripten);
+    }
+    if produced_by("AssemblyScript") {
+        return Some(AbiProfile::AssemblyScript);
+    }
+
+    let env_imports: Vec<(&str, &FuncType)> = wasm_module
+        .functions
+        .iter()
+        .filter_map(|f| match f {
+            Function::Imported { source, name, ty, .. } if source == "env" => Some((name.as_str(), ty)),
+            _ => None,
+        })
+        .collect();
+    // Plenty of toolchains import an `abort`, only AssemblyScript's takes a message, file, line and column
+    let assemblyscript_abort = |ty: &FuncType| *ty.params == [Type::I32; 4] && ty.returns.is_empty();
+    if env_imports.iter().any(|(name, _)| name.starts_with("emscripten_")) {
+        Some(AbiProfile::Emscripten)
+    } else if env_imports.iter().any(|(name, _)| name.starts_with("__syscall")) {
+        Some(AbiProfile::Musl)
+    } else if env_imports.iter().any(|&(name, ty)| name == "abort" && assemblyscript_abort(ty)) {
+        Some(AbiProfile::AssemblyScript)
+    } else {
+        None
+    }
+}
+
+// Binds the `env` imports the profile knows to the runtime or a generated shim, leaving the rest as they were
+// Returns the profile used, which with `auto` is the detected one
+pub fn bind_profile(
+    profile: AbiProfile,
+    wasm_module: &mut WasmModule,
+) -> io::Result<(AbiProfile, Vec<Option<ResolvedImport>>)> {
+    let profile = match profile {
+        AbiProfile::Auto => detect_profile(wasm_module).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not detect the toolchain that produced the module, pass --abi-profile explicitly",
+            )
+        })?,
+        p => p,
+    };
+    let has_memory = !wasm_module.memories.is_empty();
+
+    let mut resolved = Vec::new();
+    for f in &mut wasm_module.functions {
+        let (source, name, appended, ty) = match f {
+            Function::Imported {
+                source,
+                name,
+                appended,
+                ty,
+                ..
+            } => (source, name, appended, ty),
+            _ => {
+                resolved.push(None);
+                continue;
+            }
+        };
+        let known = profile_imports(profile).iter().find(|i| source == "env" && i.field == name);
+        let r = match known.map(|i| &i.binding) {
+            None => ResolvedImport::Direct,
+            Some(ProfileBinding::Runtime) => ResolvedImport::Runtime,
+            Some(ProfileBinding::Lowered(call)) => {
+                if !call.matches(ty) || !has_memory {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "import env.{} does not match the {:?} profile, it has signature {:?} -> {:?}",
+                            name, profile, ty.params, ty.returns
+                        ),
+                    ));
+                }
+                // The wasm side calls the thunk, which keeps the old name but is never visible outside
+                *appended = format!("{}_thunk", appended);
+                ResolvedImport::Lowered { call }
+            }
+            Some(ProfileBinding::Inert) => ResolvedImport::Inert,
+            Some(ProfileBinding::Trap) => ResolvedImport::Trap,
+        };
+        resolved.push(Some(r));
+    }
+    Ok((profile, resolved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::module_with_imports;
+
+    const I32: Type = Type::I32;
+
+    fn detect(imports: &[(&str, &str, &[Type], &[Type])]) -> Option<AbiProfile> {
+        detect_profile(&module_with_imports(imports, true))
+    }
+
+    #[test]
+    fn profiles_parse() {
+        assert_eq!("auto".parse(), Ok(AbiProfile::Auto));
+        assert_eq!("musl".parse(), Ok(AbiProfile::Musl));
+        assert_eq!("emscripten".parse(), Ok(AbiProfile::Emscripten));
+        assert_eq!("assemblyscript".parse(), Ok(AbiProfile::AssemblyScript));
+        assert!("wasi".parse::<AbiProfile>().is_err());
+    }
+
+    #[test]
+    fn the_producers_section_wins() {
+        let mut m = module_with_imports(&[("env", "__syscall1", &[I32, I32], &[I32])], true);
+        m.producers = vec!["AssemblyScript".to_string()];
+        assert_eq!(detect_profile(&m), Some(AbiProfile::AssemblyScript));
+        m.producers = vec!["clang".to_string(), "emscripten".to_string()];
+        assert_eq!(detect_profile(&m), Some(AbiProfile::Emscripten));
+    }
+
+    #[test]
+    fn telltale_imports_are_recognised() {
+        assert_eq!(detect(&[("env", "emscripten_memcpy_big", &[I32; 3], &[I32])]), Some(AbiProfile::Emscripten));
+        assert_eq!(detect(&[("env", "__syscall3", &[I32; 4], &[I32])]), Some(AbiProfile::Musl));
+        assert_eq!(detect(&[("env", "abort", &[I32; 4], &[])]), Some(AbiProfile::AssemblyScript));
+        assert_eq!(detect(&[("env", "f", &[], &[])]), None);
+        assert_eq!(detect(&[]), None);
+    }
+
+    #[test]
+    fn only_assemblyscripts_abort_counts() {
+        assert_eq!(detect(&[("env", "abort", &[], &[])]), None);
+        assert_eq!(detect(&[("env", "abort", &[I32; 4], &[I32])]), None);
+        assert_eq!(detect(&[("other", "abort", &[I32; 4], &[])]), None);
+    }
+
+    #[test]
+    fn auto_fails_without_a_detected_profile() {
+        let mut m = module_with_imports(&[("env", "abort", &[], &[])], true);
+        assert!(bind_profile(AbiProfile::Auto, &mut m).is_err());
+    }
+
+    #[test]
+    fn known_imports_are_bound() {
+        let mut m = module_with_imports(
+            &[
+                ("env", "abort", &[I32; 4], &[]),
+                ("env", "trace", &[I32, I32], &[]),
+                ("env", "custom", &[], &[]),
+            ],
+            true,
+        );
+        let (profile, resolved) = bind_profile(AbiProfile::Auto, &mut m).unwrap();
+        assert_eq!(profile, AbiProfile::AssemblyScript);
+        match (&resolved[0], &resolved[1], &resolved[2]) {
+            (
+                Some(ResolvedImport::Lowered { call }),
+                Some(ResolvedImport::Inert),
+                Some(ResolvedImport::Direct),
+            ) => assert_eq!(call.symbol, "assemblyscript_abort"),
+            _ => panic!("abort should be lowered, trace inert and custom left alone"),
+        }
+    }
+
+    #[test]
+    fn lowered_imports_must_match_the_profile() {
+        let mut m = module_with_imports(&[("env", "seed", &[], &[Type::F32])], true);
+        assert!(bind_profile(AbiProfile::AssemblyScript, &mut m).is_err());
+        let mut m = module_with_imports(&[("env", "seed", &[], &[Type::F64])], false);
+        assert!(bind_profile(AbiProfile::AssemblyScript, &mut m).is_err());
+    }
+}
@@ -5,6 +5,7 @@ use llvm::FunctionType;
 use llvm::Module as LLVMModule;
 use llvm::PointerType;
 use llvm::Sub;
+use llvm::Type;
 
 use crate::codegen::ModuleCtx;
 use crate::codegen::Opt;
@@ -80,6 +81,20 @@ pub const F64_SQRT: &str = "llvm.sqrt.f64";
 
 pub const TRAP: &str = "llvm.trap";
 
+// Used to find the current stack pointer for `--stack-limit-bytes`
+pub const FRAME_ADDRESS: &str = "llvm.frameaddress.p0i8";
+
+// Used by `--thread-local` to (re)populate and scrub each thread's copy of the state,
+// and by `--memory-image` to copy in the segments it could not fold
+pub const MEMCPY: &str = "llvm.memcpy.p0i8.p0i8.i64";
+pub const MEMSET: &str = "llvm.memset.p0i8.i64";
+
+// Called by `--fuel` code when the fuel counter goes negative
+pub const OUT_OF_FUEL: &str = "wasm_out_of_fuel";
+
+// Called by `--interruptible` code once the epoch reaches the deadline
+pub const EPOCH_EXPIRED: &str = "wasm_epoch_expired";
+
 pub const TABLE_ADD: &str = "add_function_to_table";
 pub const TABLE_FETCH: &str = "get_function_from_table";
 
@@ -89,6 +104,12 @@ pub const SET_GLOBAL_I32: &str = "set_global_i32";
 pub const GET_GLOBAL_I64: &str = "get_global_i64";
 pub const SET_GLOBAL_I64: &str = "set_global_i64";
 
+pub const GET_GLOBAL_F32: &str = "get_global_f32";
+pub const SET_GLOBAL_F32: &str = "set_global_f32";
+
+pub const GET_GLOBAL_F64: &str = "get_global_f64";
+pub const SET_GLOBAL_F64: &str = "set_global_f64";
+
 // Standard math operations are translated to f32.floor in WASM
 pub const FI32_FLOOR: &str = "floor";
 pub const F64_FLOOR: &str = "llvm.floor.f64";
@@ -105,9 +126,15 @@ pub const F32_CSIGN: &str = "llvm.copysign.f32";
 pub const F64_CSIGN: &str = "llvm.copysign.f64";
 
 pub const F32_CEIL: &str = "llvm.ceil.f32";
-pub const F32_NEAREST: &str = "llvm.nearbyint.f32";
 pub const F64_CEIL: &str = "llvm.ceil.f64";
-pub const F64_NEAREST: &str = "llvm.nearbyint.f64";
+
+// Wasm nearest is round-half-to-even, which rint is under the default rounding mode LLVM assumes
+// (llvm.roundeven would not depend on that, but it needs LLVM 11 and does not lower on soft-float targets)
+pub const F32_NEAREST: &str = "llvm.rint.f32";
+pub const F64_NEAREST: &str = "llvm.rint.f64";
+// nearbyint may be lowered assuming the mode can change, so it is only used by the fast unsafe implementations
+pub const F32_NEARBYINT: &str = "llvm.nearbyint.f32";
+pub const F64_NEARBYINT: &str = "llvm.nearbyint.f64";
 
 
 pub const F32_TRUNC_F32: &str = "llvm.trunc.f32";
@@ -147,7 +174,6 @@ pub fn insert_runtime_stubs(opt: &Opt, ctx: &LLVMCtx, m: &LLVMModule) {
     m.add_function(TABLE_FETCH, table_get_type.to_super());
 
     // Runtime global handling
-    /*
     if opt.use_runtime_global_handling {
         m.add_function(
             GET_GLOBAL_I32,
@@ -173,7 +199,31 @@ pub fn insert_runtime_stubs(opt: &Opt, ctx: &LLVMCtx, m: &LLVMModule) {
             )
             .to_super(),
         );
-    }*/
+        m.add_function(
+            GET_GLOBAL_F32,
+            FunctionType::new(<f32>::get_type(ctx), &[<u32>::get_type(ctx)]).to_super(),
+        );
+        m.add_function(
+            SET_GLOBAL_F32,
+            FunctionType::new(
+                <()>::get_type(ctx),
+                &[<u32>::get_type(ctx), <f32>::get_type(ctx)],
+            )
+            .to_super(),
+        );
+        m.add_function(
+            GET_GLOBAL_F64,
+            FunctionType::new(<f64>::get_type(ctx), &[<u32>::get_type(ctx)]).to_super(),
+        );
+        m.add_function(
+            SET_GLOBAL_F64,
+            FunctionType::new(
+                <()>::get_type(ctx),
+                &[<u32>::get_type(ctx), <f64>::get_type(ctx)],
+            )
+            .to_super(),
+        );
+    }
 
     let u32_rot_type = FunctionType::new(
         <u32>::get_type(ctx),
@@ -371,22 +421,6 @@ pub fn insert_runtime_stubs(opt: &Opt, ctx: &LLVMCtx, m: &LLVMModule) {
         F64_TRUNC_F64,
         FunctionType::new(<f64>::get_type(ctx), &[<f64>::get_type(ctx)]).to_super(),
     );
-    /*m.add_function(
-        F32_MIN,
-        FunctionType::new(
-            <f32>::get_type(ctx),
-            &[<f32>::get_type(ctx), <f32>::get_type(ctx)],
-        )
-        .to_super(),
-    );
-    m.add_function(
-        F32_MAX,
-        FunctionType::new(
-            <f32>::get_type(ctx),
-            &[<f32>::get_type(ctx), <f32>::get_type(ctx)],
-        )
-        .to_super(),
-    );*/
     m.add_function(
         F32_FLOOR,
         FunctionType::new(<f32>::get_type(ctx), &[<f32>::get_type(ctx)]).to_super(),
@@ -408,27 +442,21 @@ pub fn insert_runtime_stubs(opt: &Opt, ctx: &LLVMCtx, m: &LLVMModule) {
         FunctionType::new(<f64>::get_type(ctx), &[<f64>::get_type(ctx)]).to_super(),
     );
 
+    m.add_function(
+        F32_NEARBYINT,
+        FunctionType::new(<f32>::get_type(ctx), &[<f32>::get_type(ctx)]).to_super(),
+    );
+
+    m.add_function(
+        F64_NEARBYINT,
+        FunctionType::new(<f64>::get_type(ctx), &[<f64>::get_type(ctx)]).to_super(),
+    );
+
     m.add_function(
         F64_CEIL,
         FunctionType::new(<f64>::get_type(ctx), &[<f64>::get_type(ctx)]).to_super(),
     );
 
-    /*m.add_function(
-        F64_MIN,
-        FunctionType::new(
-            <f64>::get_type(ctx),
-            &[<f64>::get_type(ctx), <f64>::get_type(ctx)],
-        )
-        .to_super(),
-    );*/
-   /* m.add_function(
-        F64_MAX,
-        FunctionType::new(
-            <f64>::get_type(ctx),
-            &[<f64>::get_type(ctx), <f64>::get_type(ctx)],
-        )
-        .to_super(),
-    );*/
     m.add_function(
         F64_FLOOR,
         FunctionType::new(<f64>::get_type(ctx), &[<f64>::get_type(ctx)]).to_super(),
@@ -504,6 +532,36 @@ pub fn insert_runtime_stubs(opt: &Opt, ctx: &LLVMCtx, m: &LLVMModule) {
     );*/
 
     m.add_function(TRAP, FunctionType::new(<()>::get_type(ctx), &[]).to_super());
+
+    if opt.stack_limit_bytes.is_some() {
+        m.add_function(
+            FRAME_ADDRESS,
+            FunctionType::new(PointerType::new(<i8>::get_type(ctx)), &[<i32>::get_type(ctx)])
+                .to_super(),
+        );
+    }
+
+    if opt.thread_local || opt.memory_image {
+        let u8_ptr = PointerType::new(<u8>::get_type(ctx));
+        let i1_type: &Type = unsafe { llvm::ffi::core::LLVMInt1TypeInContext(ctx.into()).into() };
+        m.add_function(
+            MEMCPY,
+            FunctionType::new(<()>::get_type(ctx), &[u8_ptr, u8_ptr, <u64>::get_type(ctx), i1_type]).to_super(),
+        );
+        m.add_function(
+            MEMSET,
+            FunctionType::new(<()>::get_type(ctx), &[u8_ptr, <u8>::get_type(ctx), <u64>::get_type(ctx), i1_type])
+                .to_super(),
+        );
+    }
+
+    if opt.fuel {
+        m.add_function(OUT_OF_FUEL, FunctionType::new(<()>::get_type(ctx), &[]).to_super());
+    }
+
+    if opt.interruptible {
+        m.add_function(EPOCH_EXPIRED, FunctionType::new(<()>::get_type(ctx), &[]).to_super());
+    }
 }
 
 pub fn get_stub_function<'a>(m_ctx: &'a ModuleCtx, name: &str) -> &'a Function {
@@ -0,0 +1,330 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::codegen::abi::AbiFunction;
+use crate::codegen::abi::AbiType;
+use crate::codegen::abi::ModuleAbi;
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where",
+    "while", "yield",
+];
+
+fn rust_type(t: AbiType) -> &'static str {
+    match t {
+        AbiType::I32 => "i32",
+        AbiType::I64 => "i64",
+        AbiType::F32 => "f32",
+        AbiType::F64 => "f64",
+        AbiType::U32 => "u32",
+        AbiType::Bytes => "*mut u8",
+        AbiType::Instance => "*mut WasmInstance",
+    }
+}
+
+// The methods `Instance` has regardless of the module, which exports must not be named over
+const INSTANCE_METHODS: &[&str] = &["new", "as_ptr", "memory", "read_memory", "write_memory"];
+
+// Wasm names can be any string, so they need squeezing into distinct Rust identifiers
+struct Identifiers(HashSet<String>);
+
+impl Identifiers {
+    fn make(&mut self, name: &str) -> String {
+        let mut ident: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+            ident.insert(0, '_');
+        }
+        if RUST_KEYWORDS.contains(&ident.as_str()) {
+            ident.push('_');
+        }
+        let mut unique = ident.clone();
+        let mut n = 1;
+        while !self.0.insert(unique.clone()) {
+            unique = format!("{}_{}", ident, n);
+            n += 1;
+        }
+        unique
+    }
+}
+
+fn params_with_names(params: &[AbiType]) -> Vec<(String, AbiType)> {
+    params
+        .iter()
+        .enumerate()
+        .map(|(n, &t)| match t {
+            AbiType::Instance => ("instance".to_string(), t),
+            AbiType::Bytes => ("memory".to_string(), t),
+            _ => (format!("a{}", n), t),
+        })
+        .collect()
+}
+
+fn signature(params: &[(String, AbiType)], result: Option<AbiType>) -> String {
+    let params: Vec<String> = params.iter().map(|(name, t)| format!("{}: {}", name, rust_type(*t))).collect();
+    match result {
+        Some(t) => format!("({}) -> {}", params.join(", "), rust_type(t)),
+        None => format!("({})", params.join(", ")),
+    }
+}
+
+fn argument_names(params: &[(String, AbiType)]) -> String {
+    params.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+struct BoundFunction<'a> {
+    ident: String,
+    f: &'a AbiFunction,
+}
+
+fn generate_ffi(out: &mut String, entry_points: &[BoundFunction], exports: &[BoundFunction]) {
+    out.push_str("pub mod ffi {\n");
+    out.push_str("    #[allow(unused_imports)]\n");
+    out.push_str("    use super::WasmInstance;\n\n");
+    out.push_str("    extern \"C\" {\n");
+    for bound in entry_points.iter().chain(exports) {
+        let params = params_with_names(&bound.f.params);
+        out.push_str(&format!("        #[link_name = {:?}]\n", bound.f.symbol));
+        out.push_str(&format!("        pub fn {}{};\n", bound.ident, signature(&params, bound.f.result)));
+    }
+    out.push_str("    }\n}\n\n");
+}
+
+fn generate_instance(out: &mut String, abi: &ModuleAbi, entry_points: &[BoundFunction], exports: &[BoundFunction]) {
+    let has_entry_point = |name: &str| entry_points.iter().any(|e| e.f.name == name);
+    let instance_arg = |params: &[AbiType]| {
+        if params.first() == Some(&AbiType::Instance) {
+            "self.as_ptr()"
+        } else {
+            ""
+        }
+    };
+
+    out.push_str("#[derive(Debug)]\npub struct MemoryOutOfBounds;\n\n");
+
+    if abi.vmctx {
+        out.push_str("/// One instance of the module, with its own memory and globals\n");
+        out.push_str("pub struct Instance {\n");
+        out.push_str("    // u64s keep the instance struct aligned for any field it can contain\n");
+        out.push_str("    state: Vec<u64>,\n");
+        out.push_str("    memory: Vec<u8>,\n");
+        out.push_str("}\n\n");
+    } else {
+        out.push_str("/// The module's state is process (or with --thread-local, thread) wide, so this is just a handle to it\n");
+        out.push_str("pub struct Instance {\n");
+        out.push_str("    _not_send: std::marker::PhantomData<*mut ()>,\n");
+        out.push_str("}\n\n");
+        // A second handle would alias the memory, and rerun the start function over state already in use
+        if abi.thread_local {
+            out.push_str("thread_local! {\n");
+            out.push_str("    static INSTANCE_TAKEN: std::cell::Cell<bool> = std::cell::Cell::new(false);\n");
+            out.push_str("}\n\n");
+        } else {
+            out.push_str("static INSTANCE_TAKEN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);\n\n");
+        }
+    }
+
+    out.push_str("impl Instance {\n");
+    if abi.vmctx {
+        out.push_str("    pub fn new() -> Instance {\n");
+    } else if abi.thread_local {
+        out.push_str("    /// There is one copy of the state per thread, so this is None while the thread already has an Instance\n");
+        out.push_str("    pub fn new() -> Option<Instance> {\n");
+        out.push_str("        if INSTANCE_TAKEN.with(|taken| taken.replace(true)) {\n");
+        out.push_str("            return None;\n");
+        out.push_str("        }\n");
+    } else {
+        out.push_str("    /// There is only one copy of the state, so after the first call this is always None\n");
+        out.push_str("    pub fn new() -> Option<Instance> {\n");
+        out.push_str("        if INSTANCE_TAKEN.swap(true, std::sync::atomic::Ordering::SeqCst) {\n");
+        out.push_str("            return None;\n");
+        out.push_str("        }\n");
+    }
+    if abi.vmctx {
+        let initial_pages = abi.memory.as_ref().map(|m| m.initial_pages).unwrap_or(0);
+        out.push_str("        let size = unsafe { ffi::wasm_instance_size() } as usize;\n");
+        out.push_str("        let mut instance = Instance {\n");
+        out.push_str("            state: vec![0; (size + 7) / 8],\n");
+        out.push_str(&format!("            memory: vec![0; {} * WASM_PAGE_SIZE],\n", initial_pages));
+        out.push_str("        };\n");
+        out.push_str("        unsafe {\n");
+        out.push_str("            let memory_size = instance.memory.len() as u32;\n");
+        out.push_str("            ffi::wasm_instance_init(instance.as_ptr(), instance.memory.as_mut_ptr(), memory_size);\n");
+    } else {
+        out.push_str("        let mut instance = Instance {\n");
+        out.push_str("            _not_send: std::marker::PhantomData,\n");
+        out.push_str("        };\n");
+        out.push_str("        unsafe {\n");
+        if abi.thread_local {
+            out.push_str("            ffi::wasm_thread_init();\n");
+        }
+    }
+    if has_entry_point("populate_table") {
+        out.push_str("            ffi::populate_table();\n");
+    }
+    if has_entry_point("populate_globals") {
+        out.push_str("            ffi::populate_globals();\n");
+    }
+    if let Some(start) = entry_points.iter().find(|e| e.f.name == "start") {
+        let arg = instance_arg(&start.f.params).replace("self", "instance");
+        out.push_str(&format!("            ffi::{}({});\n", start.ident, arg));
+    }
+    out.push_str("        }\n");
+    if abi.vmctx {
+        out.push_str("        instance\n");
+    } else {
+        out.push_str("        Some(instance)\n");
+    }
+    out.push_str("    }\n\n");
+
+    out.push_str("    fn as_ptr(&mut self) -> *mut WasmInstance {\n");
+    if abi.vmctx {
+        out.push_str("        self.state.as_mut_ptr() as *mut WasmInstance\n");
+    } else {
+        out.push_str("        std::ptr::null_mut()\n");
+    }
+    out.push_str("    }\n");
+
+    if has_entry_point("wasm_memory") {
+        let arg = if abi.vmctx { "self.as_ptr()" } else { "" };
+        out.push_str("\n    pub fn memory(&mut self) -> &mut [u8] {\n");
+        out.push_str("        unsafe {\n");
+        out.push_str(&format!("            let base = ffi::wasm_memory({});\n", arg));
+        out.push_str(&format!("            let size = ffi::wasm_memory_size({}) as usize;\n", arg));
+        out.push_str("            std::slice::from_raw_parts_mut(base, size)\n");
+        out.push_str("        }\n");
+        out.push_str("    }\n\n");
+        out.push_str("    pub fn read_memory(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), MemoryOutOfBounds> {\n");
+        out.push_str("        let start = offset as usize;\n");
+        out.push_str("        let source = self.memory().get(start..start + buf.len()).ok_or(MemoryOutOfBounds)?;\n");
+        out.push_str("        buf.copy_from_slice(source);\n");
+        out.push_str("        Ok(())\n");
+        out.push_str("    }\n\n");
+        out.push_str("    pub fn write_memory(&mut self, offset: u32, data: &[u8]) -> Result<(), MemoryOutOfBounds> {\n");
+        out.push_str("        let start = offset as usize;\n");
+        out.push_str("        let target = self.memory().get_mut(start..start + data.len()).ok_or(MemoryOutOfBounds)?;\n");
+        out.push_str("        target.copy_from_slice(data);\n");
+        out.push_str("        Ok(())\n");
+        out.push_str("    }\n");
+    }
+
+    for bound in exports {
+        let params = params_with_names(&bound.f.params);
+        let wasm_params: Vec<(String, AbiType)> =
+            params.iter().filter(|(_, t)| *t != AbiType::Instance).cloned().collect();
+        let mut args = vec![instance_arg(&bound.f.params).to_string()];
+        args.retain(|a| !a.is_empty());
+        args.extend(wasm_params.iter().map(|(name, _)| name.clone()));
+
+        let mut method_params = vec!["&mut self".to_string()];
+        method_params.extend(wasm_params.iter().map(|(name, t)| format!("{}: {}", name, rust_type(*t))));
+        let result = bound.f.result.map(|t| format!(" -> {}", rust_type(t))).unwrap_or_default();
+
+        out.push_str(&format!("\n    /// Calls the export {:?}\n", bound.f.name));
+        out.push_str(&format!("    pub fn {}({}){} {{\n", bound.ident, method_params.join(", "), result));
+        out.push_str(&format!("        unsafe {{ ffi::{}({}) }}\n", bound.ident, args.join(", ")));
+        out.push_str("    }\n");
+    }
+    out.push_str("}\n\n");
+
+    if abi.thread_local {
+        out.push_str("impl Drop for Instance {\n");
+        out.push_str("    fn drop(&mut self) {\n");
+        out.push_str("        unsafe { ffi::wasm_thread_teardown() }\n");
+        if !abi.vmctx {
+            // Teardown scrubbed the thread's state, so a new Instance starts from scratch again
+            out.push_str("        INSTANCE_TAKEN.with(|taken| taken.set(false));\n");
+        }
+        out.push_str("    }\n");
+        out.push_str("}\n\n");
+    }
+}
+
+fn generate_imports(out: &mut String, abi: &ModuleAbi) {
+    if abi.imports.is_empty() {
+        return;
+    }
+
+    let mut idents = Identifiers(HashSet::new());
+    let methods: Vec<(String, Vec<(String, AbiType)>)> = abi
+        .imports
+        .iter()
+        .map(|i| (idents.make(&format!("{}_{}", i.module, i.field)), params_with_names(&i.params)))
+        .collect();
+
+    out.push_str("/// Implemented by the host to provide the module's imports, see `export_imports!`\n");
+    out.push_str("pub trait Imports {\n");
+    for (i, (ident, params)) in abi.imports.iter().zip(&methods) {
+        out.push_str(&format!("    /// The import {:?} from {:?}\n", i.field, i.module));
+        out.push_str(&format!("    fn {}{};\n", ident, signature(params, i.result)));
+    }
+    out.push_str("}\n\n");
+
+    out.push_str("/// Defines the native symbols the module imports, forwarding each to the `Imports` impl of `$host`\n");
+    out.push_str("/// `Imports` and `WasmInstance` must be in scope where this is invoked\n");
+    out.push_str("macro_rules! export_imports {\n");
+    out.push_str("    ($host:ty) => {\n");
+    for (n, (i, (ident, params))) in abi.imports.iter().zip(&methods).enumerate() {
+        out.push_str(&format!("        #[export_name = {:?}]\n", i.symbol));
+        out.push_str(&format!(
+            "        pub extern \"C\" fn __silverfish_import_{}{} {{\n",
+            n,
+            signature(params, i.result)
+        ));
+        out.push_str(&format!(
+            "            <$host as Imports>::{}({})\n",
+            ident,
+            argument_names(params)
+        ));
+        out.push_str("        }\n");
+    }
+    out.push_str("    };\n");
+    out.push_str("}\n");
+}
+
+// Renders the module's ABI as Rust: raw declarations in `ffi`, a safe `Instance` wrapper, and an `Imports` trait
+fn generate_rust_bindings(abi: &ModuleAbi) -> String {
+    let mut idents = Identifiers(INSTANCE_METHODS.iter().map(|m| m.to_string()).collect());
+    // `ffi` holds both lists, so they share one namespace
+    let entry_points: Vec<BoundFunction> = abi
+        .entry_points
+        .iter()
+        .map(|f| BoundFunction {
+            ident: idents.make(&f.name),
+            f,
+        })
+        .collect();
+    let exports: Vec<BoundFunction> = abi
+        .exports
+        .iter()
+        .map(|f| BoundFunction {
+            ident: idents.make(&f.name),
+            f,
+        })
+        .collect();
+
+    let mut out = String::new();
+    out.push_str(&format!("// Generated by silverfish from {}, do not edit\n", abi.source_name));
+    out.push_str("// Use as its own module, declared with #[macro_use] if the imports need exporting\n");
+    out.push_str("#![allow(dead_code, non_snake_case, unused_mut, unused_unsafe, clippy::all)]\n\n");
+
+    out.push_str("pub const WASM_PAGE_SIZE: usize = 65536;\n\n");
+
+    out.push_str("/// Opaque instance state, only ever handled through a pointer\n");
+    out.push_str("#[repr(C)]\npub struct WasmInstance {\n    _private: [u8; 0],\n}\n\n");
+
+    generate_ffi(&mut out, &entry_points, &exports);
+    generate_instance(&mut out, abi, &entry_points, &exports);
+    generate_imports(&mut out, abi);
+    out
+}
+
+pub fn write_rust_bindings(abi: &ModuleAbi, path: &Path) -> io::Result<()> {
+    fs::write(path, generate_rust_bindings(abi))
+}
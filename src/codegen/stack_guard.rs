@@ -0,0 +1,97 @@
+use llvm::BasicBlock;
+use llvm::Builder;
+use llvm::Compile;
+use llvm::Context as LLVMCtx;
+use llvm::GlobalVariable;
+use llvm::Module as LLVMModule;
+use llvm::PointerType;
+use llvm::Predicate;
+
+use crate::codegen::block::build_trap_if;
+use crate::codegen::function::FunctionCtx;
+use crate::codegen::runtime_stubs::*;
+use crate::codegen::thread_local::mark_thread_local;
+use crate::codegen::ModuleCtx;
+use crate::Opt;
+
+// The number of wasm frames currently on the stack, the runtime must zero this after a trap
+pub const CALL_DEPTH_GLOBAL: &str = "wasm_call_depth";
+// The lowest address the wasm code may push a frame below, set by the runtime's wasm_set_stack_limit
+pub const STACK_LIMIT_GLOBAL: &str = "wasm_stack_limit";
+pub const SET_STACK_LIMIT: &str = "wasm_set_stack_limit";
+// How many bytes of stack the module was compiled to be allowed, so the runtime can compute the limit
+pub const STACK_LIMIT_BYTES_GLOBAL: &str = "wasm_stack_limit_bytes";
+
+pub fn insert_stack_guard_globals<'a>(
+    opt: &Opt,
+    llvm_ctx: &'a LLVMCtx,
+    llvm_module: &'a LLVMModule,
+) -> (Option<&'a GlobalVariable>, Option<&'a GlobalVariable>) {
+    let call_depth = opt.max_call_depth.map(|_| {
+        let call_depth = llvm_module.add_global_variable(CALL_DEPTH_GLOBAL, 0u32.compile(llvm_ctx));
+        // Each thread has its own native stack, so each needs its own depth
+        mark_thread_local(opt, call_depth);
+        call_depth
+    });
+
+    let stack_limit = opt.stack_limit_bytes.map(|bytes| {
+        let bytes_global =
+            llvm_module.add_global_variable(STACK_LIMIT_BYTES_GLOBAL, bytes.compile(llvm_ctx));
+        bytes_global.set_constant(true);
+
+        // The limit itself is defined by the runtime, since only it knows where the stack starts
+        let stack_limit = llvm_module.add_global(
+            STACK_LIMIT_GLOBAL,
+            PointerType::new(<i8>::get_type(llvm_ctx)),
+        );
+        // Like the depth, every thread's stack has its own limit, which the runtime makes thread local with WASM_THREAD_LOCAL
+        mark_thread_local(opt, stack_limit);
+        stack_limit
+    });
+
+    (call_depth, stack_limit)
+}
+
+// Emits the stack checks at the top of a function, returning the basic block the body should start in
+pub fn build_stack_guard_prologue<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    entry_bb: &'a BasicBlock,
+) -> &'a BasicBlock {
+    let b = f_ctx.builder;
+    b.position_at_end(entry_bb);
+
+    let mut bb = entry_bb;
+    if let (Some(call_depth), Some(max_depth)) = (m_ctx.call_depth, m_ctx.opt.max_call_depth) {
+        let depth = b.build_add(b.build_load(call_depth), 1u32.compile(m_ctx.llvm_ctx));
+        b.build_store(depth, call_depth);
+
+        let too_deep = b.build_unsigned_cmp(
+            depth,
+            max_depth.compile(m_ctx.llvm_ctx),
+            Predicate::GreaterThan,
+        );
+        bb = build_trap_if(m_ctx, f_ctx, b, too_deep);
+    }
+
+    if let Some(stack_limit) = m_ctx.stack_limit {
+        // Stacks grow down on every target we support, so our frame must stay above the limit
+        let frame = b.build_call(
+            get_stub_function(m_ctx, FRAME_ADDRESS),
+            &[0i32.compile(m_ctx.llvm_ctx)],
+        );
+        let limit = b.build_load(stack_limit);
+        let overflow = b.build_unsigned_cmp(frame, limit, Predicate::LessThan);
+        bb = build_trap_if(m_ctx, f_ctx, b, overflow);
+    }
+
+    bb
+}
+
+// Must be emitted before every return, to undo the prologue's depth increment
+pub fn build_stack_guard_epilogue(m_ctx: &ModuleCtx, b: &Builder) {
+    if let Some(call_depth) = m_ctx.call_depth {
+        let depth = b.build_sub(b.build_load(call_depth), 1u32.compile(m_ctx.llvm_ctx));
+        b.build_store(depth, call_depth);
+    }
+}
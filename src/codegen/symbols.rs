@@ -0,0 +1,64 @@
+use std::ffi::CStr;
+use std::ffi::CString;
+
+use llvm::ffi::prelude::LLVMModuleRef;
+use llvm::ffi::prelude::LLVMValueRef;
+use llvm::Module as LLVMModule;
+
+use crate::wasm::Function;
+use crate::wasm::Global;
+use crate::wasm::WasmModule;
+use crate::Opt;
+
+// The symbol something we define ends up with, once prefix_definitions has run
+pub fn defined_symbol(opt: &Opt, name: &str) -> String {
+    match opt.symbol_prefix {
+        Some(ref prefix) => format!("{}{}", prefix, name),
+        None => name.to_string(),
+    }
+}
+
+// Imports are resolved against whatever the embedder links in, so they are prefixed separately from what we define
+pub fn prefix_imports(prefix: &str, wasm_module: &mut WasmModule) {
+    for f in &mut wasm_module.functions {
+        if let Function::Imported { appended, .. } = f {
+            *appended = format!("{}{}", prefix, appended);
+        }
+    }
+    for g in &mut wasm_module.globals {
+        if let Global::Imported { name, .. } = g {
+            *name = format!("{}{}", prefix, name);
+        }
+    }
+}
+
+// Renames every symbol the module defines: wasm functions and globals, linear memory, the table and init routines
+// Declarations are left alone, they name either imports or the runtime
+pub fn prefix_definitions(llvm_module: &LLVMModule, prefix: &str) {
+    let module_ref: LLVMModuleRef = llvm_module.into();
+
+    unsafe {
+        use llvm::ffi::core;
+
+        let mut definitions: Vec<LLVMValueRef> = Vec::new();
+        let mut f = core::LLVMGetFirstFunction(module_ref);
+        while !f.is_null() {
+            definitions.push(f);
+            f = core::LLVMGetNextFunction(f);
+        }
+        let mut g = core::LLVMGetFirstGlobal(module_ref);
+        while !g.is_null() {
+            definitions.push(g);
+            g = core::LLVMGetNextGlobal(g);
+        }
+
+        for v in definitions {
+            if core::LLVMIsDeclaration(v) != 0 {
+                continue;
+            }
+            let name = CStr::from_ptr(core::LLVMGetValueName(v)).to_string_lossy().into_owned();
+            let prefixed = CString::new(format!("{}{}", prefix, name)).unwrap();
+            core::LLVMSetValueName(v, prefixed.as_ptr());
+        }
+    }
+}
@@ -1,13 +1,29 @@
+use std::io;
+
+use llvm::ffi::prelude::LLVMTypeRef;
+use llvm::ffi::prelude::LLVMValueRef;
+use llvm::BasicBlock;
 use llvm::Builder;
 use llvm::Compile;
 use llvm::FunctionType;
+use llvm::GlobalVariable;
 use llvm::PointerType;
+use llvm::Predicate;
 use llvm::Sub;
+use llvm::Value;
+
+use wasmparser::TableType;
 
 use crate::wasm::TableInitializer;
 
 use crate::codegen::ModuleCtx;
+use crate::codegen::block::build_trap_if;
+use crate::codegen::function::FunctionCtx;
+use crate::codegen::memory::evaluate_constant_offset;
 use crate::codegen::memory::generate_offset_function;
+use crate::codegen::vmctx::build_instance_field_ptr;
+use crate::codegen::vmctx::TABLE_FIELD;
+use crate::codegen::vmctx::TABLE_SIZE_FIELD;
 
 use crate::codegen::runtime_stubs::*;
 
@@ -36,7 +52,9 @@ pub fn generate_table_initialization_stub(m_ctx: &ModuleCtx, initializers: Vec<T
             let (llvm_f, ref wasm_f) = m_ctx.functions[f_offset as usize];
 
             let table_offset = b.build_add(start_offset, (n as u32).compile(m_ctx.llvm_ctx));
-            let type_id = wasm_f.get_type_index().compile(m_ctx.llvm_ctx);
+            let type_id = m_ctx
+                .canonical_type_id(wasm_f.get_type_index())
+                .compile(m_ctx.llvm_ctx);
             let f_ptr = llvm_f.to_super();
             let f_ptr_as_u8 =
                 b.build_bit_cast(f_ptr, PointerType::new(<u8>::get_type(m_ctx.llvm_ctx)));
@@ -49,3 +67,136 @@ pub fn generate_table_initialization_stub(m_ctx: &ModuleCtx, initializers: Vec<T
     }
     b.build_ret_void();
 }
+
+pub const INLINE_TABLE: &str = "wasm_table";
+
+// Empty slots get a type id no real type can have, so calling through them fails the type check
+const EMPTY_SLOT_TYPE_ID: u32 = !0;
+
+// Each table slot is a `{type_id, fnptr}` struct
+pub fn table_entry_type(ctx: &llvm::Context) -> &llvm::Type {
+    let type_id_type: LLVMTypeRef = <u32>::get_type(ctx).into();
+    let f_ptr_type: &llvm::Type = PointerType::new(<u8>::get_type(ctx));
+    let mut entry_fields = [type_id_type, f_ptr_type.into()];
+    unsafe {
+        use llvm::ffi::core;
+
+        let raw_ctx = core::LLVMGetTypeContext(type_id_type);
+        core::LLVMStructTypeInContext(raw_ctx, entry_fields.as_mut_ptr(), 2, 0).into()
+    }
+}
+
+// Emits the whole table as a constant array of `{type_id, fnptr}`, with the element segments already applied
+// Fails if a segment's offset is only known at runtime (such as an imported global), as the table must be constant
+pub fn generate_inline_table<'a>(
+    m_ctx: &ModuleCtx<'a>,
+    table: &TableType,
+    initializers: Vec<TableInitializer>,
+) -> io::Result<&'a GlobalVariable> {
+    let table_size = table.limits.initial as usize;
+
+    let mut slots: Vec<Option<u32>> = vec![None; table_size];
+    for (n, i) in initializers.into_iter().enumerate() {
+        let offset = evaluate_constant_offset(&i.offset_expression).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "element segment {} has a non-constant offset, which an inline table (--inline-table or --vmctx) cannot support",
+                    n
+                ),
+            )
+        })? as usize;
+        if offset + i.function_indexes.len() > table_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("element segment {} does not fit in the table", n),
+            ));
+        }
+        for (n, f_index) in i.function_indexes.into_iter().enumerate() {
+            slots[offset + n] = Some(f_index);
+        }
+    }
+
+    let ctx = m_ctx.llvm_ctx;
+    let type_id_type: LLVMTypeRef = <u32>::get_type(ctx).into();
+    let f_ptr_type: &llvm::Type = PointerType::new(<u8>::get_type(ctx));
+    let f_ptr_type: LLVMTypeRef = f_ptr_type.into();
+    let entry_type: LLVMTypeRef = table_entry_type(ctx).into();
+
+    let table_value: &Value = unsafe {
+        use llvm::ffi::core;
+
+        let raw_ctx = core::LLVMGetTypeContext(type_id_type);
+
+        let mut entries: Vec<LLVMValueRef> = Vec::new();
+        for slot in slots {
+            let (type_id, f_ptr) = match slot {
+                Some(f_index) => {
+                    let (llvm_f, ref wasm_f) = m_ctx.functions[f_index as usize];
+                    let f_ptr: &Value = llvm_f.to_super();
+                    (
+                        m_ctx.canonical_type_id(wasm_f.get_type_index()),
+                        core::LLVMConstBitCast(f_ptr.into(), f_ptr_type),
+                    )
+                }
+                None => (EMPTY_SLOT_TYPE_ID, core::LLVMConstNull(f_ptr_type)),
+            };
+            let type_id: &Value = type_id.compile(ctx);
+            let mut fields = [type_id.into(), f_ptr];
+            entries.push(core::LLVMConstStructInContext(raw_ctx, fields.as_mut_ptr(), 2, 0));
+        }
+
+        core::LLVMConstArray(entry_type, entries.as_mut_ptr(), entries.len() as u32).into()
+    };
+
+    let table_global = m_ctx.llvm_module.add_global_variable(INLINE_TABLE, table_value);
+    table_global.set_constant(true);
+
+    // The runtime still calls populate_table at startup, but there is nothing left for it to do
+    let setup_function = m_ctx.llvm_module.add_function(
+        "populate_table",
+        FunctionType::new(<()>::get_type(ctx), &[]).to_super(),
+    );
+    let b = Builder::new(ctx);
+    b.position_at_end(setup_function.append("entry"));
+    b.build_ret_void();
+
+    Ok(table_global)
+}
+
+// Bounds checks and type checks an indirect call against the inline table, trapping if either fails
+// With --vmctx the table is reached through the instance instead of the global
+// Returns the untyped function pointer, and the basic block code generation should continue in
+pub fn build_inline_table_fetch<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    table: &'a GlobalVariable,
+    table_index: &'a Value,
+    type_index: u32,
+) -> (&'a Value, &'a BasicBlock) {
+    let ctx = m_ctx.llvm_ctx;
+    let zero = 0u32.compile(ctx);
+
+    let (entries, table_size) = match f_ctx.vmctx {
+        Some(_) => (
+            b.build_load(build_instance_field_ptr(m_ctx, f_ctx, b, TABLE_FIELD)),
+            b.build_load(build_instance_field_ptr(m_ctx, f_ctx, b, TABLE_SIZE_FIELD)),
+        ),
+        None => (b.build_gep(table, &[zero, zero]), m_ctx.table_size.compile(ctx)),
+    };
+
+    let out_of_bounds = b.build_unsigned_cmp(table_index, table_size, Predicate::GreaterThanOrEqual);
+    build_trap_if(m_ctx, f_ctx, b, out_of_bounds);
+
+    let entry_type_id = b.build_load(b.build_gep(entries, &[table_index, zero]));
+    let type_mismatch = b.build_unsigned_cmp(
+        entry_type_id,
+        m_ctx.canonical_type_id(type_index).compile(ctx),
+        Predicate::NotEqual,
+    );
+    let bb = build_trap_if(m_ctx, f_ctx, b, type_mismatch);
+
+    let f_ptr = b.build_load(b.build_gep(entries, &[table_index, 1u32.compile(ctx)]));
+    (f_ptr, bb)
+}
@@ -0,0 +1,126 @@
+use std::str::FromStr;
+
+use crate::Opt;
+
+// The floating point attributes clang puts on every function, some backends (cortex-m lto in particular)
+// fall back to slow defaults without them
+const STRICT_FP_ATTRIBUTES: &[(&str, &str)] = &[
+    ("correctly-rounded-divide-sqrt-fp-math", "false"),
+    ("disable-tail-calls", "false"),
+    ("less-precise-fpmad", "false"),
+    ("min-legal-vector-width", "0"),
+    ("no-frame-pointer-elim", "false"),
+    ("no-infs-fp-math", "false"),
+    ("no-jump-tables", "false"),
+    ("no-nans-fp-math", "false"),
+    ("no-signed-zeros-fp-math", "false"),
+    ("no-trapping-math", "false"),
+    ("unsafe-fp-math", "false"),
+    ("use-soft-float", "false"),
+];
+
+const THUMBV7EM_TRIPLE: &str = "thumbv7em-none-unknown-eabi";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    CortexM4,
+    CortexM7,
+    X86_64Linux,
+    Aarch64Linux,
+    Riscv32imac,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cortex-m4" => Ok(Preset::CortexM4),
+            "cortex-m7" => Ok(Preset::CortexM7),
+            "x86_64-linux" => Ok(Preset::X86_64Linux),
+            "aarch64-linux" => Ok(Preset::Aarch64Linux),
+            "riscv32imac" => Ok(Preset::Riscv32imac),
+            e => Err(format!(
+                "unknown preset {}, expected one of cortex-m4, cortex-m7, x86_64-linux, aarch64-linux, riscv32imac",
+                e
+            )),
+        }
+    }
+}
+
+// Everything we know about the machine we're compiling for
+// Unset fields are left out of the module, which makes LLVM fall back to the host
+#[derive(Clone, Debug, Default)]
+pub struct TargetSettings {
+    pub triple: Option<String>,
+    pub layout: Option<String>,
+    pub cpu: Option<String>,
+    pub features: Option<String>,
+    pub function_attributes: Vec<(&'static str, &'static str)>,
+}
+
+impl Preset {
+    fn settings(self) -> TargetSettings {
+        let (triple, cpu, features, function_attributes) = match self {
+            Preset::CortexM4 => (
+                THUMBV7EM_TRIPLE,
+                "cortex-m4",
+                "+armv7e-m,+dsp,+fp16,+fpregs,+hwdiv,+thumb-mode,+vfp2sp,+vfp3d16sp,+vfp4d16sp,-fp64,-hwdiv-arm",
+                STRICT_FP_ATTRIBUTES,
+            ),
+            Preset::CortexM7 => (
+                THUMBV7EM_TRIPLE,
+                "cortex-m7",
+                "+armv7e-m,+dsp,+fp-armv8d16,+fp-armv8d16sp,+fp16,+fp64,+fpregs,+hwdiv,+thumb-mode,+vfp2,+vfp2d16,+vfp2d16sp,+vfp2sp,+vfp3d16,+vfp3d16sp,+vfp4d16,+vfp4d16sp,-aes,-crc,-crypto,-dotprod,-fp16fml,-fullfp16,-hwdiv-arm,-lob,-mve,-mve.fp,-ras,-sb,-sha2",
+                STRICT_FP_ATTRIBUTES,
+            ),
+            Preset::X86_64Linux => (
+                "x86_64-pc-linux-gnu",
+                "x86-64",
+                "+sse,+sse2,+cx8,+fxsr,+mmx",
+                &[][..],
+            ),
+            Preset::Aarch64Linux => (
+                "aarch64-unknown-linux-gnu",
+                "generic",
+                "+neon",
+                &[][..],
+            ),
+            Preset::Riscv32imac => (
+                "riscv32-unknown-none-elf",
+                "generic-rv32",
+                "+m,+a,+c",
+                STRICT_FP_ATTRIBUTES,
+            ),
+        };
+
+        TargetSettings {
+            triple: Some(triple.to_string()),
+            // The layout is whatever LLVM's target machine for this triple uses, see target_data_layout
+            layout: None,
+            cpu: Some(cpu.to_string()),
+            features: Some(features.to_string()),
+            function_attributes: function_attributes.to_vec(),
+        }
+    }
+}
+
+// Starts from `--preset` if there is one, then lets the individual flags override pieces of it
+pub fn resolve_target(opt: &Opt) -> TargetSettings {
+    let mut settings = opt.preset.map(Preset::settings).unwrap_or_default();
+
+    if opt.target.is_some() {
+        settings.triple = opt.target.clone();
+    }
+    if opt.layout.is_some() {
+        settings.layout = opt.layout.clone();
+    }
+    if opt.target_cpu.is_some() {
+        settings.cpu = opt.target_cpu.clone();
+    }
+    if opt.target_features.is_some() {
+        settings.features = opt.target_features.clone();
+    }
+
+    settings
+}
@@ -0,0 +1,115 @@
+use std::ffi::CStr;
+use std::ffi::CString;
+
+use llvm::ffi::LLVMLinkage;
+use llvm::ffi::prelude::LLVMModuleRef;
+use llvm::ffi::prelude::LLVMValueRef;
+use llvm::Builder;
+use llvm::Compile;
+use llvm::FunctionType;
+use llvm::GlobalVariable;
+use llvm::PointerType;
+use llvm::Sub;
+use llvm::Value;
+
+use crate::codegen::runtime_stubs::get_stub_function;
+use crate::codegen::runtime_stubs::MEMCPY;
+use crate::codegen::runtime_stubs::MEMSET;
+use crate::codegen::ModuleCtx;
+use crate::Opt;
+
+pub const THREAD_INIT: &str = "wasm_thread_init";
+pub const THREAD_TEARDOWN: &str = "wasm_thread_teardown";
+
+// With --thread-local, instance state gets one copy per host thread instead of one per process
+// Constants are the same on every thread, so they stay shared (and must be set constant before calling this)
+pub fn mark_thread_local(opt: &Opt, g: &GlobalVariable) {
+    if !opt.thread_local {
+        return;
+    }
+    let g_value: &Value = g.to_super();
+    unsafe {
+        if llvm::ffi::core::LLVMIsGlobalConstant(g_value.into()) == 0 {
+            llvm::ffi::core::LLVMSetThreadLocal(g_value.into(), 1);
+        }
+    }
+}
+
+unsafe fn thread_local_definitions(m: LLVMModuleRef) -> Vec<LLVMValueRef> {
+    use llvm::ffi::core;
+
+    let mut globals = Vec::new();
+    let mut g = core::LLVMGetFirstGlobal(m);
+    while !g.is_null() {
+        // Storing to a constant is undefined behaviour, and there is nothing to repopulate in one anyway
+        if core::LLVMIsThreadLocal(g) != 0 && core::LLVMIsDeclaration(g) == 0 && core::LLVMIsGlobalConstant(g) == 0 {
+            globals.push(g);
+        }
+        g = core::LLVMGetNextGlobal(g);
+    }
+    globals
+}
+
+// A host thread calls wasm_thread_init before running wasm code, which (re)populates its copy of the state
+// wasm_thread_teardown scrubs it again, so nothing leaks into whatever runs on the thread next
+// Both go through memcpy/memset, since a memory image would otherwise be one enormous aggregate store
+pub fn generate_thread_entry_points(m_ctx: &ModuleCtx) {
+    let ctx = m_ctx.llvm_ctx;
+    let b = Builder::new(ctx);
+    let globals = unsafe { thread_local_definitions(m_ctx.llvm_module.into()) };
+    let u8_ptr = PointerType::new(<u8>::get_type(ctx));
+    let not_volatile: &Value = unsafe {
+        use llvm::ffi::core;
+        core::LLVMConstInt(core::LLVMInt1TypeInContext(ctx.into()), 0, 0).into()
+    };
+
+    let init_function = m_ctx.llvm_module.add_function(
+        THREAD_INIT,
+        FunctionType::new(<()>::get_type(ctx), &[]).to_super(),
+    );
+    b.position_at_end(init_function.append("entry"));
+    for &g in &globals {
+        let (initial, size, g): (&Value, &Value, &Value) = unsafe {
+            use llvm::ffi::core;
+            let initializer = core::LLVMGetInitializer(g);
+            let ty = core::LLVMTypeOf(initializer);
+            // TLS initializers cannot be read from another thread's copy, so keep a shared constant one to copy from
+            let name = format!("{}.initial", CStr::from_ptr(core::LLVMGetValueName(g)).to_string_lossy());
+            let c_name = CString::new(name).unwrap();
+            let initial = core::LLVMAddGlobal(m_ctx.llvm_module.into(), ty, c_name.as_ptr());
+            core::LLVMSetInitializer(initial, initializer);
+            core::LLVMSetGlobalConstant(initial, 1);
+            core::LLVMSetLinkage(initial, LLVMLinkage::LLVMPrivateLinkage);
+            core::LLVMSetAlignment(initial, core::LLVMGetAlignment(g));
+            (initial.into(), core::LLVMSizeOf(ty).into(), g.into())
+        };
+        b.build_call(
+            get_stub_function(m_ctx, MEMCPY),
+            &[b.build_bit_cast(g, u8_ptr), b.build_bit_cast(initial, u8_ptr), size, not_volatile],
+        );
+    }
+    // The segments a memory image could not fold are copied into each thread's memory after the rest
+    if m_ctx.opt.memory_image {
+        if let Some(populate_memory) = m_ctx.llvm_module.get_function("populate_memory") {
+            b.build_call(populate_memory, &[]);
+        }
+    }
+    b.build_ret_void();
+
+    let teardown_function = m_ctx.llvm_module.add_function(
+        THREAD_TEARDOWN,
+        FunctionType::new(<()>::get_type(ctx), &[]).to_super(),
+    );
+    b.position_at_end(teardown_function.append("entry"));
+    for &g in &globals {
+        let (size, g): (&Value, &Value) = unsafe {
+            use llvm::ffi::core;
+            (core::LLVMSizeOf(core::LLVMTypeOf(core::LLVMGetInitializer(g))).into(), g.into())
+        };
+        b.build_call(
+            get_stub_function(m_ctx, MEMSET),
+            &[b.build_bit_cast(g, u8_ptr), 0u8.compile(ctx), size, not_volatile],
+        );
+    }
+    b.build_ret_void();
+}
@@ -60,6 +60,15 @@ pub fn wasm_type_to_zeroed_value(ctx: &Context, wasm_type: wasmparser::Type) ->
 pub fn wasm_func_type_to_llvm_type<'a>(
     ctx: &'a Context,
     f_type: &wasmparser::FuncType,
+) -> &'a llvm::Type {
+    wasm_func_type_to_instance_llvm_type(ctx, f_type, None)
+}
+
+// With --vmctx every wasm function takes the instance pointer as a hidden first argument
+pub fn wasm_func_type_to_instance_llvm_type<'a>(
+    ctx: &'a Context,
+    f_type: &wasmparser::FuncType,
+    instance_type: Option<&'a llvm::Type>,
 ) -> &'a llvm::Type {
     let return_count = f_type.returns.len();
     assert!(return_count <= 1);
@@ -73,6 +82,10 @@ pub fn wasm_func_type_to_llvm_type<'a>(
 
     let mut params: Vec<&llvm::Type> = Vec::new();
 
+    if let Some(instance_type) = instance_type {
+        params.push(llvm::PointerType::new(instance_type));
+    }
+
     for t in &*f_type.params {
         params.push(wasm_type_to_llvm_type(ctx, *t))
     }
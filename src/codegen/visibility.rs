@@ -0,0 +1,129 @@
+use std::io;
+
+use llvm::ffi::LLVMLinkage;
+use llvm::Function as LLVMFunction;
+use llvm::Sub;
+use llvm::Value;
+
+use crate::wasm::Export;
+use crate::wasm::Function;
+use crate::wasm::Instruction;
+use crate::wasm::WasmModule;
+use crate::Opt;
+
+// Which functions make it into the output, and which of those other objects can see
+pub struct Visibility {
+    pub public: Vec<bool>,
+    pub live: Vec<bool>,
+    // Per global, whether it is an export --export-only kept, only these are described to the host
+    pub public_globals: Vec<bool>,
+}
+
+impl Visibility {
+    fn everything(wasm_module: &WasmModule) -> Visibility {
+        let function_count = wasm_module.functions.len();
+        Visibility {
+            public: vec![true; function_count],
+            live: vec![true; function_count],
+            public_globals: exported_globals(wasm_module, &[]),
+        }
+    }
+}
+
+fn exported_globals(wasm_module: &WasmModule, export_only: &[String]) -> Vec<bool> {
+    let mut public = vec![false; wasm_module.globals.len()];
+    for e in &wasm_module.exports {
+        if let Export::Global { name, index } = e {
+            if export_only.is_empty() || export_only.iter().any(|n| n == name) {
+                public[*index] = true;
+            }
+        }
+    }
+    public
+}
+
+// Public functions are the exports (narrowed by --export-only) and the start function, which the runtime has to call
+// Live functions are everything reachable from those or from the table, the rest never gets compiled
+pub fn analyze_visibility(opt: &Opt, wasm_module: &WasmModule) -> io::Result<Visibility> {
+    let function_count = wasm_module.functions.len();
+    if opt.keep_all {
+        return Ok(Visibility::everything(wasm_module));
+    }
+
+    let exported_functions: Vec<(&str, usize)> = wasm_module
+        .exports
+        .iter()
+        .filter_map(|e| match e {
+            Export::Function { name, index } => Some((name.as_str(), *index)),
+            _ => None,
+        })
+        .collect();
+
+    let is_exported_global = |name: &str| {
+        wasm_module.exports.iter().any(|e| match e {
+            Export::Global { name: n, .. } => n == name,
+            _ => false,
+        })
+    };
+    for name in &opt.export_only {
+        if !exported_functions.iter().any(|&(n, _)| n == name) && !is_exported_global(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--export-only names {}, but the module exports no such function or global", name),
+            ));
+        }
+    }
+
+    let mut public = vec![false; function_count];
+    for &(name, index) in &exported_functions {
+        if opt.export_only.is_empty() || opt.export_only.iter().any(|n| n == name) {
+            public[index] = true;
+        }
+    }
+    if let Some(start) = wasm_module.start_function {
+        public[start as usize] = true;
+    }
+
+    let mut worklist: Vec<usize> = (0..function_count).filter(|&i| public[i]).collect();
+    for i in &wasm_module.table_initializers {
+        worklist.extend(i.function_indexes.iter().map(|&f_index| f_index as usize));
+    }
+
+    let mut live = vec![false; function_count];
+    while let Some(index) = worklist.pop() {
+        if live[index] {
+            continue;
+        }
+        live[index] = true;
+
+        if let Function::Implemented { f } = &wasm_module.functions[index] {
+            for instruction in &f.code {
+                if let Instruction::Call { index } = instruction {
+                    worklist.push(*index as usize);
+                }
+            }
+        }
+    }
+
+    Ok(Visibility {
+        public,
+        live,
+        public_globals: exported_globals(wasm_module, &opt.export_only),
+    })
+}
+
+// Internal functions can be inlined into their callers and dropped, and their names stay out of the final link
+pub fn set_internal(llvm_f: &LLVMFunction) {
+    let f_value: &Value = llvm_f.to_super();
+    unsafe {
+        llvm::ffi::core::LLVMSetLinkage(f_value.into(), LLVMLinkage::LLVMInternalLinkage);
+    }
+}
+
+// Dead functions are only prototyped so function indexes stay stable during codegen, afterwards they can go
+pub fn delete_function(llvm_f: &LLVMFunction) {
+    let f_value: &Value = llvm_f.to_super();
+    unsafe {
+        llvm::ffi::core::LLVMDeleteFunction(f_value.into());
+    }
+}
@@ -0,0 +1,128 @@
+use std::ffi::CString;
+
+use llvm::ffi::prelude::LLVMModuleRef;
+use llvm::ffi::prelude::LLVMTypeRef;
+use llvm::Builder;
+use llvm::Compile;
+use llvm::Context as LLVMCtx;
+use llvm::FunctionType;
+use llvm::Module as LLVMModule;
+use llvm::PointerType;
+use llvm::Sub;
+use llvm::Value;
+
+use crate::codegen::function::FunctionCtx;
+use crate::codegen::globals::GlobalValue;
+use crate::codegen::table::table_entry_type;
+use crate::codegen::ModuleCtx;
+
+pub const INSTANCE_TYPE: &str = "wasm_instance";
+pub const INSTANCE_SIZE: &str = "wasm_instance_size";
+pub const INSTANCE_INIT: &str = "wasm_instance_init";
+
+// The fixed fields at the start of the instance struct, the module's globals follow them
+pub const MEMORY_BASE_FIELD: u32 = 0;
+pub const MEMORY_SIZE_FIELD: u32 = 1;
+pub const TABLE_FIELD: u32 = 2;
+pub const TABLE_SIZE_FIELD: u32 = 3;
+pub const FIRST_GLOBAL_FIELD: u32 = 4;
+
+// Lays out `struct wasm_instance { u8* memory; u32 memory_size; table_entry* table; u32 table_size; globals... }`
+// The host only ever sees it through wasm_instance_size and wasm_instance_init, so the layout is ours to change
+pub fn build_instance_type<'a>(
+    llvm_ctx: &'a LLVMCtx,
+    llvm_module: &'a LLVMModule,
+    globals: &[GlobalValue<'a>],
+) -> &'a llvm::Type {
+    let memory_base_type: &llvm::Type = PointerType::new(<u8>::get_type(llvm_ctx));
+    let table_type: &llvm::Type = PointerType::new(table_entry_type(llvm_ctx));
+
+    let mut fields: Vec<LLVMTypeRef> = vec![
+        memory_base_type.into(),
+        <u32>::get_type(llvm_ctx).into(),
+        table_type.into(),
+        <u32>::get_type(llvm_ctx).into(),
+    ];
+    for g in globals {
+        if let GlobalValue::Instance { initial, .. } = g {
+            fields.push(initial.get_type().into());
+        }
+    }
+
+    let module_ref: LLVMModuleRef = llvm_module.into();
+    let name = CString::new(INSTANCE_TYPE).unwrap();
+    unsafe {
+        use llvm::ffi::core;
+
+        let raw_ctx = core::LLVMGetModuleContext(module_ref);
+        let instance_type = core::LLVMStructCreateNamed(raw_ctx, name.as_ptr());
+        core::LLVMStructSetBody(instance_type, fields.as_mut_ptr(), fields.len() as u32, 0);
+        instance_type.into()
+    }
+}
+
+// Address of one field of the instance the current function was called with
+pub fn build_instance_field_ptr<'a>(
+    m_ctx: &'a ModuleCtx,
+    f_ctx: &'a FunctionCtx,
+    b: &'a Builder,
+    field: u32,
+) -> &'a Value {
+    let vmctx = f_ctx.vmctx.expect("instance state used by a function without a vmctx");
+    b.build_gep(vmctx, &[0u32.compile(m_ctx.llvm_ctx), field.compile(m_ctx.llvm_ctx)])
+}
+
+// The host allocates wasm_instance_size() bytes and linear memory, then hands both to wasm_instance_init
+// Instances share code and the (immutable) table contents, everything else is per instance
+pub fn generate_instance_api(m_ctx: &ModuleCtx) {
+    let ctx = m_ctx.llvm_ctx;
+    let instance_type = m_ctx.instance_type.unwrap();
+    let b = Builder::new(ctx);
+
+    let size_function = m_ctx.llvm_module.add_function(
+        INSTANCE_SIZE,
+        FunctionType::new(<u32>::get_type(ctx), &[]).to_super(),
+    );
+    b.position_at_end(size_function.append("entry"));
+    let size: &Value = unsafe {
+        use llvm::ffi::core;
+        core::LLVMConstTruncOrBitCast(core::LLVMSizeOf(instance_type.into()), <u32>::get_type(ctx).into()).into()
+    };
+    b.build_ret(size);
+
+    let init_function = m_ctx.llvm_module.add_function(
+        INSTANCE_INIT,
+        FunctionType::new(
+            <()>::get_type(ctx),
+            &[
+                PointerType::new(instance_type),
+                PointerType::new(<u8>::get_type(ctx)),
+                <u32>::get_type(ctx),
+            ],
+        )
+        .to_super(),
+    );
+    b.position_at_end(init_function.append("entry"));
+    let instance: &Value = &*init_function[0];
+    let field_ptr = |field: u32| b.build_gep(instance, &[0u32.compile(ctx), field.compile(ctx)]);
+
+    b.build_store(&*init_function[1], field_ptr(MEMORY_BASE_FIELD));
+    b.build_store(&*init_function[2], field_ptr(MEMORY_SIZE_FIELD));
+
+    let table_ptr = match m_ctx.table {
+        Some(table) => b.build_gep(table, &[0u32.compile(ctx), 0u32.compile(ctx)]),
+        None => {
+            let table_ptr_type: &llvm::Type = PointerType::new(table_entry_type(ctx));
+            unsafe { llvm::ffi::core::LLVMConstNull(table_ptr_type.into()).into() }
+        }
+    };
+    b.build_store(table_ptr, field_ptr(TABLE_FIELD));
+    b.build_store(m_ctx.table_size.compile(ctx), field_ptr(TABLE_SIZE_FIELD));
+
+    for g in m_ctx.globals {
+        if let GlobalValue::Instance { field, initial } = g {
+            b.build_store(initial, field_ptr(*field));
+        }
+    }
+    b.build_ret_void();
+}
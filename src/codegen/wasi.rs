@@ -0,0 +1,197 @@
+use std::io;
+
+use wasmparser::Type;
+
+use crate::codegen::imports::ResolvedImport;
+use crate::codegen::lowering::LoweredCall;
+use crate::codegen::lowering::LoweredParam;
+use crate::codegen::lowering::LoweredParam::*;
+use crate::wasm::Function;
+use crate::wasm::WasmModule;
+
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+// WASI's errno for an argument pointing outside of linear memory
+// A bad pointer makes the call fail with it, as a WASI host would, rather than trapping
+const ERRNO_FAULT: i32 = 21;
+
+const fn wasi(name: &'static str, symbol: &'static str, params: &'static [LoweredParam]) -> LoweredCall {
+    LoweredCall {
+        name,
+        symbol,
+        params,
+        result: Some(Type::I32),
+        fault_errno: Some(ERRNO_FAULT),
+    }
+}
+
+// The preview1 calls the runtime implements, the rest are rejected at compile time
+const WASI_CALLS: &[LoweredCall] = &[
+    wasi("args_get", "wasi_args_get", &[Offset, Offset]),
+    wasi("args_sizes_get", "wasi_args_sizes_get", &[Pointer(4), Pointer(4)]),
+    wasi("environ_get", "wasi_environ_get", &[Offset, Offset]),
+    wasi("environ_sizes_get", "wasi_environ_sizes_get", &[Pointer(4), Pointer(4)]),
+    wasi("clock_res_get", "wasi_clock_res_get", &[Scalar(Type::I32), Pointer(8)]),
+    wasi(
+        "clock_time_get",
+        "wasi_clock_time_get",
+        &[Scalar(Type::I32), Scalar(Type::I64), Pointer(8)],
+    ),
+    wasi("fd_close", "wasi_fd_close", &[Scalar(Type::I32)]),
+    wasi("fd_fdstat_get", "wasi_fd_fdstat_get", &[Scalar(Type::I32), Pointer(24)]),
+    wasi("fd_prestat_get", "wasi_fd_prestat_get", &[Scalar(Type::I32), Pointer(8)]),
+    wasi("fd_prestat_dir_name", "wasi_fd_prestat_dir_name", &[Scalar(Type::I32), Buffer(1)]),
+    wasi("fd_read", "wasi_fd_read", &[Scalar(Type::I32), Buffer(8), Pointer(4)]),
+    wasi(
+        "fd_seek",
+        "wasi_fd_seek",
+        &[Scalar(Type::I32), Scalar(Type::I64), Scalar(Type::I32), Pointer(8)],
+    ),
+    wasi("fd_write", "wasi_fd_write", &[Scalar(Type::I32), Buffer(8), Pointer(4)]),
+    LoweredCall {
+        name: "proc_exit",
+        symbol: "wasi_proc_exit",
+        params: &[Scalar(Type::I32)],
+        result: None,
+        fault_errno: None,
+    },
+    wasi("random_get", "wasi_random_get", &[Buffer(1)]),
+    wasi("sched_yield", "wasi_sched_yield", &[]),
+];
+
+// Points every wasi_snapshot_preview1 import at a thunk into the runtime's WASI implementation
+// Fails listing every call the runtime lacks, or whose signature is not the one WASI defines
+pub fn bind_wasi(
+    wasm_module: &mut WasmModule,
+    previous: Option<Vec<Option<ResolvedImport>>>,
+) -> io::Result<Vec<Option<ResolvedImport>>> {
+    let has_memory = !wasm_module.memories.is_empty();
+    let mut previous = previous.map(Vec::into_iter);
+    let mut resolved = Vec::new();
+    let mut unsupported = Vec::new();
+    for f in &mut wasm_module.functions {
+        let earlier = previous.as_mut().and_then(Iterator::next).flatten();
+        let (source, name, appended, ty) = match f {
+            Function::Imported {
+                source,
+                name,
+                appended,
+                ty,
+                ..
+            } => (source, name, appended, ty),
+            _ => {
+                resolved.push(None);
+                continue;
+            }
+        };
+        if source != WASI_MODULE {
+            resolved.push(Some(earlier.unwrap_or(ResolvedImport::Direct)));
+            continue;
+        }
+
+        match WASI_CALLS.iter().find(|c| c.name == name) {
+            Some(c) if c.matches(ty) && has_memory => {
+                // The wasm side calls the thunk, which keeps the old name but is never visible outside
+                *appended = format!("{}_thunk", appended);
+                resolved.push(Some(ResolvedImport::Lowered { call: c }));
+            }
+            Some(_) if !has_memory => {
+                unsupported.push(format!("{} (the module has no memory)", name));
+                resolved.push(None);
+            }
+            Some(_) => {
+                unsupported.push(format!("{} (signature {:?} -> {:?})", name, ty.params, ty.returns));
+                resolved.push(None);
+            }
+            None => {
+                unsupported.push(name.clone());
+                resolved.push(None);
+            }
+        }
+    }
+
+    if !unsupported.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported {} imports: {}", WASI_MODULE, unsupported.join(", ")),
+        ));
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::module_with_imports;
+
+    const I32: Type = Type::I32;
+
+    #[test]
+    fn supported_calls_are_lowered() {
+        let mut m = module_with_imports(
+            &[
+                (WASI_MODULE, "fd_write", &[I32, I32, I32, I32], &[I32]),
+                (WASI_MODULE, "proc_exit", &[I32], &[]),
+                ("env", "f", &[], &[]),
+            ],
+            true,
+        );
+        let resolved = bind_wasi(&mut m, None).unwrap();
+        match (&resolved[0], &resolved[1], &resolved[2]) {
+            (
+                Some(ResolvedImport::Lowered { call: fd_write }),
+                Some(ResolvedImport::Lowered { call: proc_exit }),
+                Some(ResolvedImport::Direct),
+            ) => {
+                assert_eq!(fd_write.symbol, "wasi_fd_write");
+                assert_eq!(fd_write.fault_errno, Some(ERRNO_FAULT));
+                assert_eq!(proc_exit.symbol, "wasi_proc_exit");
+            }
+            _ => panic!("the WASI calls should be lowered and env.f left alone"),
+        }
+        match &m.functions[0] {
+            Function::Imported { appended, .. } => assert_eq!(appended, "wasi_snapshot_preview1_fd_write_thunk"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn every_unsupported_call_is_reported() {
+        let mut m = module_with_imports(
+            &[
+                (WASI_MODULE, "path_open", &[I32], &[I32]),
+                (WASI_MODULE, "fd_close", &[I32, I32], &[I32]),
+                (WASI_MODULE, "sock_recv", &[], &[I32]),
+            ],
+            true,
+        );
+        let e = bind_wasi(&mut m, None).err().unwrap().to_string();
+        assert!(e.contains("path_open"));
+        assert!(e.contains("fd_close (signature"));
+        assert!(e.contains("sock_recv"));
+    }
+
+    #[test]
+    fn lowering_needs_a_memory() {
+        let mut m = module_with_imports(&[(WASI_MODULE, "sched_yield", &[], &[I32])], false);
+        let e = bind_wasi(&mut m, None).err().unwrap().to_string();
+        assert!(e.contains("sched_yield (the module has no memory)"));
+    }
+
+    #[test]
+    fn other_modules_keep_earlier_bindings() {
+        let mut m = module_with_imports(&[("env", "f", &[], &[]), (WASI_MODULE, "sched_yield", &[], &[I32])], true);
+        let resolved = bind_wasi(&mut m, Some(vec![Some(ResolvedImport::Inert), None])).unwrap();
+        match (&resolved[0], &resolved[1]) {
+            (Some(ResolvedImport::Inert), Some(ResolvedImport::Lowered { .. })) => {}
+            _ => panic!("env.f should stay inert"),
+        }
+    }
+
+    #[test]
+    fn the_table_has_no_duplicates() {
+        for (n, call) in WASI_CALLS.iter().enumerate() {
+            assert!(WASI_CALLS[n + 1..].iter().all(|other| other.name != call.name), "{}", call.name);
+        }
+    }
+}
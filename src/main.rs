@@ -1,7 +1,10 @@
 extern crate llvm;
 #[macro_use]
 extern crate log;
+extern crate serde;
+extern crate serde_json;
 extern crate structopt;
+extern crate toml;
 extern crate wasmparser;
 
 use std::fs::File;
@@ -16,6 +19,10 @@ use wasmparser::Parser;
 
 mod codegen;
 use crate::codegen::process_to_llvm;
+use crate::codegen::AbiProfile;
+use crate::codegen::EmitKind;
+use crate::codegen::OptLevel;
+use crate::codegen::Preset;
 
 mod wasm;
 use crate::wasm::WasmModule;
@@ -29,10 +36,30 @@ pub struct Opt {
     #[structopt(name = "input", parse(from_os_str))]
     input: PathBuf,
 
-    /// Output bc file
+    /// Output file, defaults to `output` with an extension matching `--emit`
     #[structopt(short = "o", long = "output")]
     output: Option<String>,
 
+    /// What to write out: bc, ll, asm or obj
+    #[structopt(long = "emit", default_value = "bc")]
+    emit: EmitKind,
+
+    /// Optimize the module in-process before writing it: -O0, -O1, -O2, -O3, -Os or -Oz
+    #[structopt(short = "O")]
+    opt_level: Option<OptLevel>,
+
+    /// Run the LLVM verifier on the generated module, and report which wasm function broke it
+    #[structopt(long = "verify")]
+    verify: bool,
+
+    /// Link this runtime bitcode file into the output, internalizing its symbols except weak ones
+    #[structopt(long = "link-runtime", parse(from_os_str))]
+    link_runtime: Option<PathBuf>,
+
+    /// Leave these runtime symbols visible after --link-runtime, comma separated, for host APIs called from outside
+    #[structopt(long = "keep-symbol", requires = "link-runtime", use_delimiter = true)]
+    keep_symbols: Vec<String>,
+
     /// Force inlining of constant globals
     #[structopt(short = "i", long = "inline-constant-globals")]
     inline_constant_globals: bool,
@@ -41,10 +68,110 @@ pub struct Opt {
     #[structopt(short = "u", long = "fast-unsafe-implementations")]
     use_fast_unsafe_implementations: bool,
 
-    /// Don't generate native globals, let the runtime handle it
-    #[structopt(long = "runtime-globals")]
+    /// Don't generate native globals, let the runtime handle them through get and set stubs indexed by global
+    #[structopt(long = "runtime-globals", conflicts_with = "vmctx")]
     use_runtime_global_handling: bool,
 
+    /// Canonicalize NaNs and forbid float contraction, so results are bit-identical across targets
+    #[structopt(long = "deterministic")]
+    deterministic: bool,
+
+    /// Trap when wasm calls nest deeper than this many frames
+    #[structopt(long = "max-call-depth")]
+    max_call_depth: Option<u32>,
+
+    /// Trap when wasm uses more than this many bytes of native stack below where `wasm_set_stack_limit` was called
+    #[structopt(long = "stack-limit-bytes")]
+    stack_limit_bytes: Option<u32>,
+
+    /// Meter execution with a fuel counter the host can read and refill
+    #[structopt(long = "fuel")]
+    fuel: bool,
+
+    /// The fuel execution starts with, unlimited by default for hosts that set their own budget
+    #[structopt(long = "initial-fuel", requires = "fuel")]
+    initial_fuel: Option<i64>,
+
+    /// Check a runtime written epoch against a deadline at function entries and loop headers
+    #[structopt(long = "interruptible")]
+    interruptible: bool,
+
+    /// Emit the indirect call table as a constant global, instead of filling a runtime table at startup
+    #[structopt(long = "inline-table")]
+    inline_table: bool,
+
+    /// Keep every call_indirect as a table lookup, even when the possible targets are known
+    #[structopt(long = "no-devirtualize")]
+    no_devirtualize: bool,
+
+    /// Keep only these exports visible, comma separated; other exported functions become internal,
+    /// and other exported globals are left out of the header, bindings and manifest
+    #[structopt(long = "export-only", use_delimiter = true)]
+    export_only: Vec<String>,
+
+    /// Keep every function with external linkage, even unexported and unreachable ones
+    #[structopt(long = "keep-all")]
+    keep_all: bool,
+
+    /// Keep memory, globals and the table in a per-instance struct passed to every function as a hidden first argument
+    /// The host sizes and sets up instances with wasm_instance_size and wasm_instance_init
+    #[structopt(long = "vmctx")]
+    vmctx: bool,
+
+    /// Give each host thread its own linear memory and globals, set up by wasm_thread_init and wasm_thread_teardown
+    ///
+    /// Runtime globals live in the runtime's one process-wide array, so they cannot be made per thread
+    #[structopt(long = "thread-local", conflicts_with_all = &["vmctx", "runtime-globals"])]
+    thread_local: bool,
+
+    /// Bind imports to native symbols with a TOML manifest, checking their signatures
+    #[structopt(long = "imports", parse(from_os_str))]
+    imports: Option<PathBuf>,
+
+    /// Lower wasi_snapshot_preview1 imports onto the runtime's WASI implementation, rejecting calls it lacks
+    #[structopt(long = "wasi")]
+    wasi: bool,
+
+    /// Bind the env imports of a toolchain (auto, musl, emscripten, assemblyscript) to the runtime or generated shims
+    #[structopt(long = "abi-profile")]
+    abi_profile: Option<AbiProfile>,
+
+    /// Mangle import and export symbols reversibly, as wasm_import_<module>_s<field> and wasm_export_<name>
+    #[structopt(long = "mangle")]
+    mangle: bool,
+
+    /// Write a TOML file mapping every import and export to its native symbol
+    #[structopt(long = "symbol-map", parse(from_os_str))]
+    symbol_map: Option<PathBuf>,
+
+    /// Write a C header declaring the exports, imports, globals and entry points of the compiled module
+    #[structopt(long = "emit-header", parse(from_os_str))]
+    emit_header: Option<PathBuf>,
+
+    /// Write Rust bindings for the compiled module, with a safe wrapper over the exports and a trait for the imports
+    #[structopt(long = "emit-rust-bindings", parse(from_os_str))]
+    emit_rust_bindings: Option<PathBuf>,
+
+    /// Write a JSON manifest of what the compiled object contains, for build systems
+    #[structopt(long = "emit-manifest", parse(from_os_str))]
+    emit_manifest: Option<PathBuf>,
+
+    /// Fold constant offset data segments into a pre-initialized linear memory global, instead of copying them in at startup
+    #[structopt(long = "memory-image", conflicts_with = "vmctx")]
+    memory_image: bool,
+
+    /// Place the memory image in this section, and the zeroed rest of initial memory in `.bss.<section>` right after it
+    #[structopt(long = "memory-image-section", requires = "memory-image")]
+    memory_image_section: Option<String>,
+
+    /// Prefix every symbol the module defines, so several sandboxes can be linked into one binary
+    #[structopt(long = "symbol-prefix")]
+    symbol_prefix: Option<String>,
+
+    /// Prefix the symbols wasm imports are resolved against, independently of --symbol-prefix
+    #[structopt(long = "import-prefix")]
+    import_prefix: Option<String>,
+
     /// Set compilation target
     #[structopt(long = "target")]
     target: Option<String>,
@@ -53,6 +180,19 @@ pub struct Opt {
     #[structopt(long = "layout")]
     layout: Option<String>,
 
+    /// Set the cpu functions are tuned for, such as cortex-m7
+    #[structopt(long = "target-cpu")]
+    target_cpu: Option<String>,
+
+    /// Set target features, such as +dsp,+fp64
+    #[structopt(long = "target-features")]
+    target_features: Option<String>,
+
+    /// Use a named target profile: cortex-m4, cortex-m7, x86_64-linux, aarch64-linux or riscv32imac
+    /// Explicit --target, --layout, --target-cpu and --target-features flags override the preset
+    #[structopt(long = "preset")]
+    preset: Option<Preset>,
+
 
     /// Generate rotl operations as pure math
     #[structopt(long = "expand-rot")]
@@ -83,7 +223,7 @@ fn main() -> io::Result<()> {
     let output_path = opt
         .output
         .clone()
-        .unwrap_or_else(|| "output.bc".to_string());
+        .unwrap_or_else(|| format!("output.{}", opt.emit.default_extension()));
     process_to_llvm(&opt, module, &output_path)?;
 
     info!("silverfish finished successfully");
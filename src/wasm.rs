@@ -1,6 +1,7 @@
 use std::str;
 
 use wasmparser::{CustomSectionKind, TypeOrFuncType};
+use wasmparser::BinaryReader;
 use wasmparser::ExternalKind;
 use wasmparser::FuncType;
 use wasmparser::ImportSectionEntryType;
@@ -19,6 +20,8 @@ pub struct WasmModule {
     name_counter: u64,
 
     pub types: Vec<FuncType>,
+    // For each type, the index of the first structurally identical type
+    pub canonical_types: Vec<u32>,
     pub globals: Vec<Global>,
     pub functions: Vec<Function>,
 
@@ -29,11 +32,16 @@ pub struct WasmModule {
     pub table_initializers: Vec<TableInitializer>,
 
     pub exports: Vec<Export>,
+    pub start_function: Option<u32>,
+    // Every tool and language named in the `producers` custom section, if there was one
+    pub producers: Vec<String>,
 }
 
 #[derive(Clone, Debug)]
 pub enum Global {
     Imported {
+        source: String,
+        field: String,
         name: String,
         content_type: Type,
         mutable: bool,
@@ -46,6 +54,27 @@ pub enum Global {
     },
 }
 
+// The producers section is a list of fields (language, processed-by, sdk), each a list of (name, version)
+// Only the names matter to us, and a malformed section is treated as naming nothing
+fn parse_producers(data: &[u8]) -> Vec<String> {
+    let mut reader = BinaryReader::new(data);
+    let mut names = Vec::new();
+    let mut read = || -> wasmparser::Result<()> {
+        for _ in 0..reader.read_var_u32()? {
+            reader.read_string()?;
+            for _ in 0..reader.read_var_u32()? {
+                names.push(reader.read_string()?.to_string());
+                reader.read_string()?;
+            }
+        }
+        Ok(())
+    };
+    match read() {
+        Ok(()) => names,
+        Err(_) => Vec::new(),
+    }
+}
+
 impl Global {
     pub fn set_name(&mut self, new_name: String) {
         *self = match *self {
@@ -785,6 +814,7 @@ enum ProcessState {
     TableSection,
     MemorySection,
     ExportSection,
+    StartSection,
     CodeSection,
     FunctionCode(ImplementedFunction),
 
@@ -834,6 +864,7 @@ impl WasmModule {
             source_name: input_filename.to_string(),
             name_counter: 0,
             types: Vec::new(),
+            canonical_types: Vec::new(),
             globals: Vec::new(),
             functions: Vec::new(),
             tables: Vec::new(),
@@ -841,15 +872,32 @@ impl WasmModule {
             memories: Vec::new(),
             data_initializers: Vec::new(),
             exports: Vec::new(),
+            start_function: None,
+            producers: Vec::new(),
         }
     }
 
     pub fn from_wasm_parser(input_filename: &str, p: &mut Parser) -> WasmModule {
         let mut m = WasmModule::new(input_filename);
         m.process_wasm(p);
+        m.canonicalize_types();
         m
     }
 
+    // Indirect calls check signatures structurally, so duplicate types in the type section must share an id
+    fn canonicalize_types(&mut self) {
+        let types = &self.types;
+        self.canonical_types = types
+            .iter()
+            .map(|ty| {
+                types
+                    .iter()
+                    .position(|other| other.params == ty.params && other.returns == ty.returns)
+                    .unwrap() as u32
+            })
+            .collect();
+    }
+
     pub fn log_diagnostics(&self) {
         let global_variable_memory_use: usize = self.globals.iter().map(|g| g.in_memory_size()).sum();
         info!("Globals taking up {} bytes", global_variable_memory_use);
@@ -907,6 +955,7 @@ impl WasmModule {
                 SectionCode::Table => ProcessState::TableSection,
                 SectionCode::Memory => ProcessState::MemorySection,
                 SectionCode::Export => ProcessState::ExportSection,
+                SectionCode::Start => ProcessState::StartSection,
                 SectionCode::Code => ProcessState::CodeSection,
                 SectionCode::Data => ProcessState::DataSection,
                 SectionCode::Element => ProcessState::TableElementSection,
@@ -925,12 +974,16 @@ impl WasmModule {
     fn process_custom_section(
         &mut self,
         p: &mut Parser,
-        _: Vec<u8>,
+        name: Vec<u8>,
         _: CustomSectionKind,
     ) -> ProcessState {
         loop {
             match p.read() {
-                &ParserState::SectionRawData(_) => {}
+                &ParserState::SectionRawData(data) => {
+                    if name == b"producers" {
+                        self.producers = parse_producers(data);
+                    }
+                }
                 &ParserState::EndSection => return ProcessState::Outer,
                 e => panic!("Have not implemented custom section state {:?}", e),
             }
@@ -982,9 +1035,11 @@ impl WasmModule {
                     ImportSectionEntryType::Global(global_ty) => {
                         let source = module.to_string();
                         let name = field.to_string();
-                        let appended = source + "_" + &name;
+                        let appended = source.clone() + "_" + &name;
 
                         self.globals.push(Global::Imported {
+                            source,
+                            field: name,
                             name: appended,
                             content_type: global_ty.content_type,
                             mutable: global_ty.mutable,
@@ -1052,6 +1107,17 @@ impl WasmModule {
         }
     }
 
+    fn process_start_section(&mut self, p: &mut Parser) -> ProcessState {
+        match p.read() {
+            &ParserState::StartSectionEntry(index) => {
+                self.start_function = Some(index);
+                ProcessState::StartSection
+            }
+            &ParserState::EndSection => ProcessState::Outer,
+            e => panic!("Have not implemented start section state {:?}", e),
+        }
+    }
+
     fn process_code_section(&mut self, p: &mut Parser) -> ProcessState {
         match p.read() {
             &ParserState::BeginFunctionBody { .. } => {
@@ -1307,6 +1373,7 @@ impl WasmModule {
                 ProcessState::TableSection => self.process_table_section(p),
                 ProcessState::MemorySection => self.process_memory_section(p),
                 ProcessState::ExportSection => self.process_export_section(p),
+                ProcessState::StartSection => self.process_start_section(p),
                 ProcessState::CodeSection => self.process_code_section(p),
                 ProcessState::FunctionCode(f) => self.process_function_code(p, f),
                 ProcessState::DataSection => self.process_data_section(p),
@@ -1348,3 +1415,52 @@ impl WasmModule {
         }
     }
 }
+
+// Builds a module that only has function imports (and optionally a memory), for testing the passes that bind them
+#[cfg(test)]
+pub fn module_with_imports(imports: &[(&str, &str, &[Type], &[Type])], memory: bool) -> WasmModule {
+    fn value_type(t: Type) -> u8 {
+        match t {
+            Type::I32 => 0x7f,
+            Type::I64 => 0x7e,
+            Type::F32 => 0x7d,
+            Type::F64 => 0x7c,
+            t => panic!("no value type encoding for {:?}", t),
+        }
+    }
+    // Every count and length here is below 128, so it is a single byte of LEB128
+    fn section(bytes: &mut Vec<u8>, id: u8, contents: Vec<u8>) {
+        bytes.push(id);
+        bytes.push(contents.len() as u8);
+        bytes.extend(contents);
+    }
+    fn name(contents: &mut Vec<u8>, s: &str) {
+        contents.push(s.len() as u8);
+        contents.extend(s.as_bytes());
+    }
+
+    let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+    let mut types = vec![imports.len() as u8];
+    for (_, _, params, results) in imports {
+        types.push(0x60);
+        types.push(params.len() as u8);
+        types.extend(params.iter().map(|t| value_type(*t)));
+        types.push(results.len() as u8);
+        types.extend(results.iter().map(|t| value_type(*t)));
+    }
+    section(&mut bytes, 1, types);
+
+    let mut entries = vec![imports.len() as u8];
+    for (n, (module, field, _, _)) in imports.iter().enumerate() {
+        name(&mut entries, module);
+        name(&mut entries, field);
+        entries.extend(&[0x00, n as u8]);
+    }
+    section(&mut bytes, 2, entries);
+
+    if memory {
+        section(&mut bytes, 5, vec![1, 0x00, 1]);
+    }
+
+    WasmModule::from_wasm_parser("test.wasm", &mut Parser::new(&bytes))
+}
@@ -286,9 +286,10 @@ check_testops "$TYPES_I" "$T_OPS_I"
 #echo
 #check_extend "i32" "$EXTEND_OPS_I32"
 
+# min and max are lowered to compares and selects, since llvm.minimum/maximum do not lower everywhere
 bash test1.sh f32_min.wat
 
-if ! grep -q f32.min "f32_min.wat.mirror.wat"; then
+if ! grep -q select "f32_min.wat.mirror.wat"; then
 	error f32_min.wat.mirror.wat
 fi
 
@@ -296,7 +297,7 @@ printf "Checking f32.min ${COLOR_GREEN} ${CHANGES_ICON} ${NC}\n"
 
 bash test1.sh f32_max.wat
 
-if ! grep -q f32.max "f32_max.wat.mirror.wat"; then
+if ! grep -q select "f32_max.wat.mirror.wat"; then
 	error f32_max.wat.mirror.wat
 fi
 
@@ -304,7 +305,7 @@ printf "Checking f32.max ${COLOR_GREEN} ${CHANGES_ICON} ${NC}\n"
 
 bash test1.sh f64_max.wat
 
-if ! grep -q f64.max "f64_max.wat.mirror.wat"; then
+if ! grep -q select "f64_max.wat.mirror.wat"; then
 	error f64_max.wat.mirror.wat
 fi
 
@@ -312,7 +313,7 @@ printf "Checking f64.max ${COLOR_GREEN} ${CHANGES_ICON} ${NC}\n"
 
 bash test1.sh f64_min.wat
 
-if ! grep -q f64.min "f64_min.wat.mirror.wat"; then
+if ! grep -q select "f64_min.wat.mirror.wat"; then
 	error f64_min.wat.mirror.wat
 fi
 